@@ -0,0 +1,232 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use clap::{parser::ValueSource, ArgAction, CommandFactory, FromArgMatches};
+use toml_edit::{DocumentMut, Item, Value};
+
+use crate::cli::Cli;
+
+/// Parse the command line, filling in anything it doesn't give from an `.rdp` file
+/// and then the selected profile. Profiles live in a TOML file as tables named `[profiles.NAME]`, with
+/// keys named after the options, e.g. `keyboard_layout = "de-DE"` or
+/// `dynamic_channels = ["echo"]`, and `host` for the host.
+pub fn parse_cli() -> Cli {
+    let args: Vec<OsString> = std::env::args_os().collect();
+
+    // A first pass to find the profile and which options were given, without
+    // insisting on the ones a profile may provide.
+    let matches = Cli::command()
+        .mut_arg("username", |arg| {
+            arg.required(false)
+                .required_unless_present(clap::builder::Resettable::Reset)
+        })
+        .mut_arg("host", |arg| arg.required(false))
+        .try_get_matches_from(&args)
+        .unwrap_or_else(|e| e.exit());
+    let profile = matches.get_one::<String>("profile");
+    let rdp_file = matches.get_one::<PathBuf>("rdp_file");
+    if profile.is_none() && rdp_file.is_none() {
+        return Cli::from_arg_matches(&Cli::command().get_matches_from(args))
+            .unwrap_or_else(|e| e.exit());
+    }
+
+    let explicit = |id: &str| {
+        matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    let extra_args = (|| {
+        // An .rdp file takes precedence over the profile.
+        let rdp_settings = match rdp_file {
+            Some(path) => crate::rdp_file::read_settings(path)?,
+            None => Vec::new(),
+        };
+        let from_rdp_file = |id: &str| rdp_settings.iter().any(|(key, _)| key == id);
+        let mut extra_args = match profile {
+            Some(profile) => {
+                let path = matches
+                    .get_one::<PathBuf>("config")
+                    .cloned()
+                    .or_else(default_config_path)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("No config file given and no home directory to look in")
+                    })?;
+                read_profile_args(&path, profile, |id| explicit(id) || from_rdp_file(id))?
+            }
+            None => Vec::new(),
+        };
+        extra_args.extend(settings_args(rdp_settings, explicit)?);
+        anyhow::Ok(extra_args)
+    })()
+    .unwrap_or_else(|e| {
+        Cli::command()
+            .error(clap::error::ErrorKind::InvalidValue, e)
+            .exit()
+    });
+
+    let args = args
+        .iter()
+        .take(1)
+        .cloned()
+        .chain(extra_args.into_iter().map(OsString::from))
+        .chain(args.iter().skip(1).cloned());
+    Cli::from_arg_matches(&Cli::command().get_matches_from(args)).unwrap_or_else(|e| e.exit())
+}
+
+/// The directory holding our configuration and the known hosts file.
+pub fn config_dir() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(dir.join("rdp-channel-client"))
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("config.toml"))
+}
+
+fn read_profile_args(
+    path: &Path,
+    profile: &str,
+    explicit: impl Fn(&str) -> bool,
+) -> anyhow::Result<Vec<String>> {
+    let config = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+    profile_args(&config, profile, explicit)
+        .map_err(|e| anyhow::anyhow!("{} ({})", e, path.display()))
+}
+
+/// Options which only make sense on the command line.
+static UNSETTABLE: &[&str] = &["profile", "config", "rdp_file", "export_rdp"];
+
+/// Turn a profile into command line arguments.
+fn profile_args(
+    config: &str,
+    profile: &str,
+    explicit: impl Fn(&str) -> bool,
+) -> anyhow::Result<Vec<String>> {
+    let config: DocumentMut = config.parse()?;
+    let settings = config
+        .get("profiles")
+        .and_then(|profiles| profiles.get(profile))
+        .and_then(Item::as_table_like)
+        .ok_or_else(|| anyhow::anyhow!("No profile '{}'", profile))?;
+
+    let settings = settings
+        .iter()
+        .map(|(key, item)| {
+            toml_values(item)
+                .map(|values| (key.to_string(), values))
+                .ok_or_else(|| anyhow::anyhow!("Unsupported value for '{}' in profile", key))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    settings_args(settings, explicit)
+}
+
+/// Turn option ids and values into command line arguments, leaving out options for
+/// which `explicit` says the command line has its own value.
+fn settings_args(
+    settings: Vec<(String, Vec<String>)>,
+    explicit: impl Fn(&str) -> bool,
+) -> anyhow::Result<Vec<String>> {
+    let command = Cli::command();
+    let mut args = Vec::new();
+    let mut positionals = Vec::new();
+    for (key, values) in settings {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == key.as_str() && !UNSETTABLE.contains(&key.as_str()))
+            .ok_or_else(|| anyhow::anyhow!("Unknown setting '{}'", key))?;
+        if explicit(&key) {
+            continue;
+        }
+
+        match arg.get_long() {
+            None => positionals.extend(values),
+            Some(long) if matches!(arg.get_action(), ArgAction::SetTrue) => {
+                match values.as_slice() {
+                    [value] if value == "true" => args.push(format!("--{}", long)),
+                    [value] if value == "false" => {}
+                    _ => return Err(anyhow::anyhow!("Expected true or false for '{}'", key)),
+                }
+            }
+            // The = form keeps values starting with a dash from looking like options.
+            Some(long) => args.extend(values.iter().map(|value| format!("--{}={}", long, value))),
+        }
+    }
+    args.extend(positionals);
+    Ok(args)
+}
+
+fn toml_values(item: &Item) -> Option<Vec<String>> {
+    let scalar = |value: &Value| match value {
+        Value::String(s) => Some(s.value().clone()),
+        Value::Integer(i) => Some(i.value().to_string()),
+        Value::Float(f) => Some(f.value().to_string()),
+        Value::Boolean(b) => Some(b.value().to_string()),
+        _ => None,
+    };
+    match item.as_value()? {
+        Value::Array(values) => values.iter().map(scalar).collect(),
+        value => scalar(value).map(|value| vec![value]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[profiles.vm1]
+host = "10.0.0.5"
+username = "tester"
+port = 3390
+relative_mouse = true
+software_pointer = false
+dynamic_channels = ["echo", "telemetry"]
+type_text = "-dash"
+
+[profiles.vm2]
+hostname = "typo"
+"#;
+
+    #[test]
+    fn profile_to_args() {
+        let args = profile_args(CONFIG, "vm1", |_| false).unwrap();
+        assert_eq!(
+            args,
+            [
+                "--username=tester",
+                "--port=3390",
+                "--relative-mouse",
+                "--dynamic-channels=echo",
+                "--dynamic-channels=telemetry",
+                "--type-text=-dash",
+                "10.0.0.5",
+            ]
+        );
+        let cli = Cli::from_arg_matches(
+            &Cli::command().get_matches_from(std::iter::once("rcc".to_string()).chain(args)),
+        )
+        .unwrap();
+        assert_eq!(cli.host.to_string(), "10.0.0.5");
+        assert_eq!(cli.port, 3390);
+        assert_eq!(cli.type_text.as_deref(), Some("-dash"));
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let args = profile_args(CONFIG, "vm1", |id| id == "host" || id == "port").unwrap();
+        assert!(!args
+            .iter()
+            .any(|arg| arg == "10.0.0.5" || arg.starts_with("--port")));
+    }
+
+    #[test]
+    fn bad_profiles() {
+        assert!(profile_args(CONFIG, "vm2", |_| false).is_err());
+        assert!(profile_args(CONFIG, "vm3", |_| false).is_err());
+    }
+}
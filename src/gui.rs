@@ -1,17 +1,94 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use eframe::egui::{
-    self, load::SizedTexture, Color32, ColorImage, Event, Image, TextureHandle, TextureOptions,
+    self, load::SizedTexture, Color32, ColorImage, CursorIcon, Image, Key, KeyboardShortcut,
+    Modifiers, Pos2, Rect, TextureHandle, TextureOptions, Vec2, ViewportCommand,
 };
 use ironrdp::pdu::input::fast_path::FastPathInputEvent;
 
-use crate::rdp::{keyboard::RDPKeyboardEvents, RDPMousePosition, RDPSharedFramebuffer};
+use crate::keyboard_grab::KeyboardGrab;
+use crate::rdp::{
+    cert::RDPCertificatePrompt,
+    combo::{KeyCombo, KeyMacro, KeySequence, SPECIAL_KEY_COMBOS},
+    finalize::RDPServerInput,
+    keyboard::{send_keystrokes, RDPKeyboardMode, RDPKeyboardState, RDPLockKeys},
+    layout::KeyboardLayout,
+    mouse::{clamp_position, RDPMouseState, RDPPointerCapture},
+    RDPMousePosition, RDPPointerUpdate, RDPSharedFramebuffer,
+};
+
+/// How often to look for lock keys toggled while the window has focus.
+const LOCK_KEY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Height of the status bar below the RDP view.
+pub const STATUS_BAR_HEIGHT: f32 = 20.0;
+
+/// The pointer shape most recently set by the server.
+enum RemotePointer {
+    Default,
+    Hidden,
+    Bitmap {
+        texture: TextureHandle,
+        hotspot: Vec2,
+    },
+}
+
+/// Asks for the password, or smart card PIN, when none was given on the command line.
+pub struct LoginPrompt {
+    /// e.g. "Password for tester on vm1"
+    pub label: String,
+    pub password_tx: tokio::sync::oneshot::Sender<String>,
+}
+
+/// User preferences for how input is forwarded to the session.
+pub struct AppOptions {
+    pub relative_mouse: bool,
+    /// What input the server accepts, once connected.
+    pub server_input: tokio::sync::watch::Receiver<RDPServerInput>,
+    pub keyboard_mode: RDPKeyboardMode,
+    pub keyboard_layout: Option<KeyboardLayout>,
+    /// Sent, followed by `type_text`, once the desktop first appears.
+    pub send_keys: Vec<KeySequence>,
+    pub type_text: String,
+    /// Pause after each key sequence or character sent automatically.
+    pub key_delay: Duration,
+    pub macros: Vec<KeyMacro>,
+    /// Grab the keyboard whenever the window has focus.
+    pub grab_keyboard: bool,
+    /// Toggles pointer capture and keyboard grab.
+    pub release_key: KeyboardShortcut,
+    pub login_prompt: Option<LoginPrompt>,
+    /// Questions from the session about server certificates which have changed.
+    pub certificate_prompts: tokio::sync::mpsc::UnboundedReceiver<RDPCertificatePrompt>,
+    /// Why the session failed; closed without a reason if it ended normally.
+    pub session_end: tokio::sync::oneshot::Receiver<String>,
+}
 
 pub struct App {
     texture_handle: TextureHandle,
     rx: tokio::sync::watch::Receiver<Arc<Mutex<RDPSharedFramebuffer>>>,
     mouse_tx: tokio::sync::watch::Sender<RDPMousePosition>,
     rdp_input_tx: tokio::sync::mpsc::Sender<Vec<FastPathInputEvent>>,
+    mouse_state: RDPMouseState,
+    keyboard_state: RDPKeyboardState,
+    relative_mouse: bool,
+    server_input: tokio::sync::watch::Receiver<RDPServerInput>,
+    pointer_rx: tokio::sync::mpsc::UnboundedReceiver<RDPPointerUpdate>,
+    remote_pointer: RemotePointer,
+    last_lock_key_check: Instant,
+    macros: Vec<KeyMacro>,
+    keyboard_grab: KeyboardGrab,
+    grab_keyboard: bool,
+    release_key: KeyboardShortcut,
+    login_prompt: Option<LoginPrompt>,
+    password_input: String,
+    certificate_prompts: tokio::sync::mpsc::UnboundedReceiver<RDPCertificatePrompt>,
+    certificate_prompt: Option<RDPCertificatePrompt>,
+    session_end: Option<tokio::sync::oneshot::Receiver<String>>,
+    session_error: Option<String>,
 }
 
 impl App {
@@ -20,61 +97,507 @@ impl App {
         rx: tokio::sync::watch::Receiver<Arc<Mutex<RDPSharedFramebuffer>>>,
         mouse_tx: tokio::sync::watch::Sender<RDPMousePosition>,
         rdp_input_tx: tokio::sync::mpsc::Sender<Vec<FastPathInputEvent>>,
+        pointer_rx: tokio::sync::mpsc::UnboundedReceiver<RDPPointerUpdate>,
         tctx: tokio::sync::oneshot::Sender<egui::Context>,
+        options: AppOptions,
     ) -> Self {
         let texture_handle =
             cc.egui_ctx
                 .load_texture("rdp", ColorImage::example(), TextureOptions::default());
-        tctx.send(cc.egui_ctx.clone())
-            .expect("Failed to pass egui context to RDP session.");
+        // Should the session have failed already, that is reported separately.
+        let _ = tctx.send(cc.egui_ctx.clone());
         // We can then update the image via set partial
         // texture_handle.set_partial(pos, image, options);
+
+        // The server expects a synchronize event before any keyboard input. Where the
+        // local state is unknown, leave the server's as it is rather than turning
+        // everything off.
+        let mut keyboard_state =
+            RDPKeyboardState::new(options.keyboard_mode, options.keyboard_layout);
+        if let Some(fp) = RDPLockKeys::local()
+            .and_then(|lock_keys| keyboard_state.sync_lock_keys(lock_keys, true))
+        {
+            // Should the session have failed already, that is reported separately.
+            let _ = rdp_input_tx.blocking_send(fp);
+        }
+
+        let keystrokes: Vec<Vec<FastPathInputEvent>> = options
+            .send_keys
+            .iter()
+            .map(KeySequence::events)
+            .chain(keyboard_state.text_keystrokes(&options.type_text))
+            .collect();
+        if !keystrokes.is_empty() {
+            let mut frames = rx.clone();
+            let input_tx = rdp_input_tx.clone();
+            let delay = options.key_delay;
+            std::thread::spawn(move || {
+                let rt = tokio::runtime::Builder::new_current_thread()
+                    .enable_time()
+                    .build()
+                    .expect("Failed to start key automation runtime");
+                rt.block_on(async {
+                    // Wait for the desktop to appear, as anything typed before then is lost.
+                    frames.changed().await?;
+                    send_keystrokes(&input_tx, keystrokes, delay).await
+                })
+                .unwrap_or_else(|e| log::error!("Failed to send keys: {}", e));
+            });
+        }
+
         Self {
             texture_handle,
             rx,
             mouse_tx,
             rdp_input_tx,
+            mouse_state: RDPMouseState::default(),
+            keyboard_state,
+            relative_mouse: options.relative_mouse,
+            server_input: options.server_input,
+            pointer_rx,
+            remote_pointer: RemotePointer::Default,
+            last_lock_key_check: Instant::now(),
+            macros: options.macros,
+            keyboard_grab: KeyboardGrab::new(),
+            grab_keyboard: options.grab_keyboard,
+            release_key: options.release_key,
+            login_prompt: options.login_prompt,
+            password_input: String::new(),
+            certificate_prompts: options.certificate_prompts,
+            certificate_prompt: None,
+            session_end: Some(options.session_end),
+            session_error: None,
+        }
+    }
+
+    /// Pass input on to the session. Once it has ended there is nowhere for input to
+    /// go, which is dealt with when the session reports its end.
+    fn send_input(&self, fp: Vec<FastPathInputEvent>) {
+        if self.rdp_input_tx.blocking_send(fp).is_err() {
+            log::debug!("Dropped input for the RDP session, which has ended");
+        }
+    }
+
+    /// Show why the session failed, or close the window if it ended normally.
+    fn check_session_end(&mut self, ctx: &egui::Context, frame: &eframe::Frame) {
+        let Some(session_end) = &mut self.session_end else {
+            return;
+        };
+        match session_end.try_recv() {
+            Err(tokio::sync::oneshot::error::TryRecvError::Empty) => return,
+            Err(tokio::sync::oneshot::error::TryRecvError::Closed) => {
+                ctx.send_viewport_cmd(ViewportCommand::Close);
+            }
+            Ok(error) => self.session_error = Some(error),
+        }
+        self.session_end = None;
+        // Give the pointer and keyboard back so the error can be dismissed.
+        self.set_pointer_capture(ctx, false);
+        self.set_keyboard_grab(frame, false);
+        self.update_title(ctx);
+    }
+
+    fn session_error_dialog(&self, ctx: &egui::Context) {
+        let Some(error) = &self.session_error else {
+            return;
+        };
+        egui::Window::new("Disconnected")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(error);
+                if ui.button("Close").clicked() {
+                    ctx.send_viewport_cmd(ViewportCommand::Close);
+                }
+            });
+    }
+
+    /// Keep the server's lock keys in step with the local ones. egui has no events
+    /// for them, so resynchronize on regaining focus and poll while focused.
+    fn sync_lock_keys(&mut self, ctx: &egui::Context) {
+        let focused_in = ctx.input(|i| i.events.contains(&egui::Event::WindowFocused(true)));
+        let focused = ctx.input(|i| i.focused);
+        if focused {
+            ctx.request_repaint_after(LOCK_KEY_POLL_INTERVAL);
+        }
+        let poll_due = focused && self.last_lock_key_check.elapsed() >= LOCK_KEY_POLL_INTERVAL;
+        if !focused_in && !poll_due {
+            return;
+        }
+        self.last_lock_key_check = Instant::now();
+
+        let Some(lock_keys) = RDPLockKeys::local().or(self.keyboard_state.lock_keys()) else {
+            return;
+        };
+        if let Some(fp) = self.keyboard_state.sync_lock_keys(lock_keys, focused_in) {
+            self.send_input(fp);
+        }
+    }
+
+    fn login_dialog(&mut self, ctx: &egui::Context) {
+        let Some(prompt) = &self.login_prompt else {
+            return;
+        };
+        let mut submitted = false;
+        egui::Window::new("Log in")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(&prompt.label);
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut self.password_input)
+                        .password(true)
+                        .desired_width(240.0),
+                );
+                response.request_focus();
+                submitted = response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter));
+                submitted |= ui.button("Connect").clicked();
+            });
+
+        if submitted {
+            if let Some(prompt) = self.login_prompt.take() {
+                let password = std::mem::take(&mut self.password_input);
+                // The session thread only goes away if it has already failed.
+                let _ = prompt.password_tx.send(password);
+            }
+        }
+    }
+
+    fn certificate_dialog(&mut self, ctx: &egui::Context) {
+        let Some(prompt) = &self.certificate_prompt else {
+            return;
+        };
+        let mut accepted = None;
+        egui::Window::new("Server certificate changed")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "The certificate for {} is not the one seen before, which could mean \
+                     the connection is being intercepted.",
+                    prompt.host
+                ));
+                ui.label(format!("Previously: {}", prompt.known_fingerprint));
+                ui.label(format!("Now: {}", prompt.fingerprint));
+                ui.horizontal(|ui| {
+                    if ui.button("Connect anyway").clicked() {
+                        accepted = Some(true);
+                    }
+                    if ui.button("Cancel").clicked() {
+                        accepted = Some(false);
+                    }
+                });
+            });
+
+        if let Some(accepted) = accepted {
+            if let Some(prompt) = self.certificate_prompt.take() {
+                let _ = prompt.reply.send(accepted);
+            }
         }
     }
+
+    fn status_bar(&self, ui: &mut egui::Ui) {
+        ui.horizontal_centered(|ui| {
+            // Offer the key combinations which the local desktop would otherwise grab.
+            ui.menu_button("Keys", |ui| {
+                let mut fp = None;
+                for (name, keys) in SPECIAL_KEY_COMBOS {
+                    if ui.button(*name).clicked() {
+                        fp = Some(KeyCombo::new(keys).events());
+                    }
+                }
+                if !self.macros.is_empty() {
+                    ui.separator();
+                }
+                for key_macro in &self.macros {
+                    if ui.button(&key_macro.name).clicked() {
+                        fp = Some(key_macro.sequence.events());
+                    }
+                }
+                if let Some(fp) = fp {
+                    ui.close_menu();
+                    self.send_input(fp);
+                }
+            });
+            ui.separator();
+
+            // This is what we last told the server, which may since have changed its
+            // own lock state; nothing is shown until the local state is known.
+            if let Some(lock_keys) = self.keyboard_state.lock_keys() {
+                ui.label(egui::RichText::new("Sent:").small().weak())
+                    .on_hover_text("Lock key state last sent to the server");
+                for (on, name) in [
+                    (lock_keys.caps_lock, "CAPS"),
+                    (lock_keys.num_lock, "NUM"),
+                    (lock_keys.scroll_lock, "SCRL"),
+                    (lock_keys.kana_lock, "KANA"),
+                ] {
+                    let text = egui::RichText::new(name).small().monospace();
+                    ui.label(if on { text.strong() } else { text.weak() });
+                }
+            }
+        });
+    }
+
+    fn update_pointer(&mut self, ctx: &egui::Context, update: RDPPointerUpdate) {
+        match update {
+            RDPPointerUpdate::Default => self.remote_pointer = RemotePointer::Default,
+            RDPPointerUpdate::Hidden => self.remote_pointer = RemotePointer::Hidden,
+            RDPPointerUpdate::Position { x, y } => {
+                // The server moved the pointer, e.g. to snap to a default button.
+                if self.mouse_state.capture() == RDPPointerCapture::Released {
+                    ctx.send_viewport_cmd(ViewportCommand::CursorPosition(egui::pos2(
+                        f32::from(x),
+                        f32::from(y),
+                    )));
+                }
+            }
+            RDPPointerUpdate::Bitmap(bitmap) => {
+                let image = ColorImage::from_rgba_unmultiplied(
+                    [bitmap.width as usize, bitmap.height as usize],
+                    &bitmap.data,
+                );
+                let texture = ctx.load_texture("rdp_pointer", image, TextureOptions::NEAREST);
+                let hotspot = egui::vec2(f32::from(bitmap.hotspot_x), f32::from(bitmap.hotspot_y));
+                self.remote_pointer = RemotePointer::Bitmap { texture, hotspot };
+            }
+        }
+    }
+
+    /// Draw the server's pointer over the RDP view in place of the local cursor. Doing
+    /// this locally rather than having the server composite it into the framebuffer
+    /// means it tracks the mouse without waiting for a round trip.
+    fn draw_pointer(&self, ctx: &egui::Context, bounds: Rect) {
+        let pos = match self.mouse_state.capture() {
+            RDPPointerCapture::Released => ctx
+                .input(|i| i.pointer.hover_pos())
+                .filter(|pos| bounds.contains(*pos)),
+            RDPPointerCapture::Synthesized => {
+                let (x, y) = self.mouse_state.position();
+                Some(egui::pos2(f32::from(x), f32::from(y)))
+            }
+            // We don't know where the server has put the pointer.
+            RDPPointerCapture::Relative => None,
+        };
+
+        let Some(pos) = pos else {
+            return;
+        };
+        match &self.remote_pointer {
+            RemotePointer::Default => {}
+            RemotePointer::Hidden => ctx.set_cursor_icon(CursorIcon::None),
+            RemotePointer::Bitmap { texture, hotspot } => {
+                ctx.set_cursor_icon(CursorIcon::None);
+                let painter = ctx.layer_painter(egui::LayerId::new(
+                    egui::Order::Tooltip,
+                    egui::Id::new("rdp_pointer"),
+                ));
+                painter.image(
+                    texture.id(),
+                    Rect::from_min_size(pos - *hotspot, texture.size_vec2()),
+                    Rect::from_min_max(Pos2::ZERO, egui::pos2(1.0, 1.0)),
+                    Color32::WHITE,
+                );
+            }
+        }
+    }
+
+    /// Hide and lock the local cursor so that raw mouse motion can be forwarded,
+    /// or give it back.
+    fn set_pointer_capture(&mut self, ctx: &egui::Context, captured: bool) {
+        let capture = match (captured, self.relative_mouse) {
+            (false, _) => RDPPointerCapture::Released,
+            (true, true) if self.server_input.borrow().relative_mouse => {
+                RDPPointerCapture::Relative
+            }
+            (true, true) => {
+                log::warn!(
+                    "The server doesn't accept relative mouse events, so moving the pointer itself"
+                );
+                RDPPointerCapture::Synthesized
+            }
+            (true, false) => RDPPointerCapture::Synthesized,
+        };
+        if capture == self.mouse_state.capture() {
+            return;
+        }
+        let was_synthesized = self.mouse_state.capture() == RDPPointerCapture::Synthesized;
+
+        let fp = self.mouse_state.set_capture(capture);
+        if !fp.is_empty() {
+            self.send_input(fp);
+        }
+
+        if captured {
+            // Not every platform supports both grab modes, so ask for confinement first
+            // and let locking override it where available.
+            ctx.send_viewport_cmd(ViewportCommand::CursorGrab(egui::CursorGrab::Confined));
+            ctx.send_viewport_cmd(ViewportCommand::CursorGrab(egui::CursorGrab::Locked));
+            ctx.send_viewport_cmd(ViewportCommand::CursorVisible(false));
+        } else {
+            if was_synthesized {
+                // Put the local cursor where the remote one ended up.
+                let (x, y) = self.mouse_state.position();
+                ctx.send_viewport_cmd(ViewportCommand::CursorPosition(egui::pos2(
+                    f32::from(x),
+                    f32::from(y),
+                )));
+            }
+            ctx.send_viewport_cmd(ViewportCommand::CursorGrab(egui::CursorGrab::None));
+            ctx.send_viewport_cmd(ViewportCommand::CursorVisible(true));
+        }
+    }
+
+    fn set_keyboard_grab(&mut self, frame: &eframe::Frame, grab: bool) {
+        if !self.grab_keyboard {
+            return;
+        }
+        if let Err(e) = self.keyboard_grab.set(frame, grab) {
+            log::warn!("Disabling keyboard grab: {}", e);
+            self.grab_keyboard = false;
+        }
+    }
+
+    /// Say in the title bar what is captured and how to get it back.
+    fn update_title(&self, ctx: &egui::Context) {
+        let pointer = self.mouse_state.capture() != RDPPointerCapture::Released;
+        let captured = match (pointer, self.keyboard_grab.is_grabbed()) {
+            (true, true) => "pointer and keyboard",
+            (true, false) => "pointer",
+            (false, true) => "keyboard",
+            (false, false) => {
+                ctx.send_viewport_cmd(ViewportCommand::Title("RDP".to_string()));
+                return;
+            }
+        };
+        ctx.send_viewport_cmd(ViewportCommand::Title(format!(
+            "RDP - {} captured ({} to release)",
+            captured,
+            ctx.format_shortcut(&self.release_key)
+        )));
+    }
 }
 
 impl eframe::App for App {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        self.check_session_end(ctx, frame);
+        if self.session_error.is_some() {
+            self.session_error_dialog(ctx);
+            return;
+        }
+        if self.certificate_prompt.is_none() {
+            self.certificate_prompt = self.certificate_prompts.try_recv().ok();
+        }
+        if self.certificate_prompt.is_some() {
+            self.certificate_dialog(ctx);
+            return;
+        }
+        if self.login_prompt.is_some() {
+            // Keep input away from the session until it has been set up, lest the
+            // password end up being typed into it.
+            self.login_dialog(ctx);
+            return;
+        }
+
+        self.sync_lock_keys(ctx);
+        egui::TopBottomPanel::bottom("status_bar")
+            .exact_height(STATUS_BAR_HEIGHT)
+            .show(ctx, |ui| self.status_bar(ui));
+
         egui::CentralPanel::default()
             .frame(egui::Frame::NONE) // Remove default borders around the RDP view.
             .show(ctx, |ui| {
                 ui.with_layout(egui::Layout::left_to_right(egui::Align::Min), |ui| {
-                    if let Some(pos) = ctx.input(|i| i.pointer.hover_pos()) {
-                        let bounds = ctx.screen_rect();
-                        let x = f32::min(f32::max(pos.x, bounds.min.x), bounds.max.x) as u16;
-                        let y = f32::min(f32::max(pos.y, bounds.min.y), bounds.max.y) as u16;
+                    while let Ok(update) = self.pointer_rx.try_recv() {
+                        self.update_pointer(ctx, update);
+                    }
+
+                    if ctx.input_mut(|i| i.consume_shortcut(&self.release_key)) {
+                        let captured = self.mouse_state.capture() != RDPPointerCapture::Released
+                            || self.keyboard_grab.is_grabbed();
+                        self.set_pointer_capture(ctx, !captured);
+                        self.set_keyboard_grab(frame, !captured);
+                        self.update_title(ctx);
+                    }
+                    if ctx.input(|i| i.events.contains(&egui::Event::WindowFocused(false))) {
+                        self.set_pointer_capture(ctx, false);
+                        self.set_keyboard_grab(frame, false);
+                        self.update_title(ctx);
+                    }
+                    if ctx.input(|i| i.events.contains(&egui::Event::WindowFocused(true))) {
+                        self.set_keyboard_grab(frame, true);
+                        self.update_title(ctx);
+                    }
+
+                    let bounds = ui.max_rect();
+                    if self.keyboard_state.mode() == RDPKeyboardMode::Unicode {
+                        // egui only enables the platform IME while something asks for it,
+                        // which would normally be a focused text edit.
+                        let cursor = ctx.input(|i| i.pointer.hover_pos()).unwrap_or(bounds.min);
+                        ctx.output_mut(|o| {
+                            o.ime = Some(egui::output::IMEOutput {
+                                rect: bounds,
+                                cursor_rect: Rect::from_min_size(cursor, Vec2::ZERO),
+                            })
+                        });
+                    }
+
+                    let captured = self.mouse_state.capture() != RDPPointerCapture::Released;
+                    // Keep following drags which leave the view, but not mere hovering
+                    // over the status bar.
+                    let dragging = ctx.input(|i| i.pointer.any_down());
+                    if let Some(pos) = ctx
+                        .input(|i| i.pointer.hover_pos())
+                        .filter(|pos| !captured && (dragging || bounds.contains(*pos)))
+                    {
+                        let (x, y) = clamp_position(pos, bounds);
                         let last_pos = self.mouse_tx.borrow().clone();
                         if last_pos.x != x || last_pos.y != y {
-                            self.mouse_tx
-                                .send(RDPMousePosition { x, y })
-                                .expect("Tried to send mouse input to non-existant RDP session");
+                            // Ignored once the session has ended, as for other input.
+                            let _ = self.mouse_tx.send(RDPMousePosition { x, y });
                         }
                     }
 
+                    let hover = ctx.input(|i| i.pointer.hover_pos());
+                    if ctx.input(|i| i.focused) {
+                        self.keyboard_state
+                            .set_local_modifiers(self.keyboard_grab.modifiers_down(frame));
+                    }
                     ui.input(|input| {
-                        let keyboard_events: Vec<RDPKeyboardEvents> = input
+                        let mut fp: Vec<FastPathInputEvent> = input
                             .events
                             .iter()
-                            .filter_map(|event| RDPKeyboardEvents::maybe_from(event))
+                            .filter_map(|event| self.keyboard_state.maybe_from(event))
+                            .flat_map(|e| e.into_fastpath_events())
                             .collect();
-                        if keyboard_events.len() > 0 {
-                            let fp: Vec<FastPathInputEvent> = keyboard_events
-                                .into_iter()
-                                .flat_map(|e| e.as_fastpath_events())
-                                .collect();
-                            self.rdp_input_tx
-                                .blocking_send(fp)
-                                .expect("Tried to send keyboard input to non-existant RDP session");
+                        // egui doesn't report modifiers being pressed on their own, so pick
+                        // up any change since the last key event (e.g. for shift-click).
+                        if input.focused {
+                            fp.extend(self.keyboard_state.sync_modifiers(&input.modifiers));
+                        }
+                        if !fp.is_empty() {
+                            self.send_input(fp);
+                        }
+
+                        // Button and wheel events carry their own position, so unlike pointer
+                        // moves they go through the ordered input channel.
+                        let fp: Vec<FastPathInputEvent> = input
+                            .events
+                            .iter()
+                            .filter(|event| captured || !starts_outside(event, bounds, hover))
+                            .filter_map(|event| self.mouse_state.maybe_from(event, bounds))
+                            .flat_map(|e| e.into_fastpath_events())
+                            .collect();
+                        if !fp.is_empty() {
+                            self.send_input(fp);
                         }
                     });
 
-                    // TODO handle possible error.
-                    if self.rx.has_changed().unwrap() {
+                    // The last frame stays up if the session has gone.
+                    if self.rx.has_changed().unwrap_or(false) {
                         {
                             let shared_framebuffer = self.rx.borrow().clone();
                             let mut locked = shared_framebuffer
@@ -106,7 +629,47 @@ impl eframe::App for App {
                         ))
                         .shrink_to_fit(),
                     );
+
+                    self.draw_pointer(ctx, bounds);
                 });
             });
     }
 }
+
+/// Whether a mouse event begins an interaction outside the RDP view, such as a click
+/// on the status bar. Releases still go through so that drags can end outside.
+fn starts_outside(event: &egui::Event, bounds: Rect, hover: Option<Pos2>) -> bool {
+    match event {
+        egui::Event::PointerButton {
+            pos, pressed: true, ..
+        } => !bounds.contains(*pos),
+        egui::Event::MouseWheel { .. } => hover.is_some_and(|pos| !bounds.contains(pos)),
+        _ => false,
+    }
+}
+
+/// Parse a shortcut such as `ctrl+alt+g`; the last part names the key.
+pub fn parse_shortcut(s: &str) -> anyhow::Result<KeyboardShortcut> {
+    let mut parts: Vec<&str> = s.split('+').map(str::trim).collect();
+    let key_name = parts.pop().unwrap_or_default();
+    let key = Key::ALL
+        .iter()
+        .find(|key| key.name().eq_ignore_ascii_case(key_name))
+        .copied()
+        .ok_or_else(|| anyhow::anyhow!("Unknown key '{}'", key_name))?;
+
+    let mut modifiers = Modifiers::NONE;
+    for part in parts {
+        modifiers = modifiers.plus(match part.to_ascii_lowercase().as_str() {
+            "ctrl" | "control" => Modifiers::CTRL,
+            "alt" => Modifiers::ALT,
+            "shift" => Modifiers::SHIFT,
+            "cmd" | "command" => Modifiers::COMMAND,
+            _ => return Err(anyhow::anyhow!("Unknown modifier '{}'", part)),
+        });
+    }
+    if modifiers.is_none() {
+        return Err(anyhow::anyhow!("A release key needs at least one modifier"));
+    }
+    Ok(KeyboardShortcut::new(modifiers, key))
+}
@@ -0,0 +1,123 @@
+use raw_window_handle::{HasDisplayHandle, HasWindowHandle, RawDisplayHandle, RawWindowHandle};
+
+use crate::rdp::keyboard::RDPModifier;
+
+/// Grabs the keyboard for the session window, so that shortcuts such as Alt+Tab
+/// reach the server rather than the local window manager. winit has no API for
+/// this, so it is done directly through Xlib and is unavailable elsewhere. The
+/// same goes for telling which hand's modifier keys are down.
+pub struct KeyboardGrab {
+    #[cfg(target_os = "linux")]
+    xlib: Option<x11_dl::xlib::Xlib>,
+    grabbed: bool,
+}
+
+impl KeyboardGrab {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(target_os = "linux")]
+            xlib: x11_dl::xlib::Xlib::open().ok(),
+            grabbed: false,
+        }
+    }
+
+    pub fn is_grabbed(&self) -> bool {
+        self.grabbed
+    }
+
+    pub fn set(
+        &mut self,
+        window: &(impl HasWindowHandle + HasDisplayHandle),
+        grab: bool,
+    ) -> anyhow::Result<()> {
+        if grab == self.grabbed {
+            return Ok(());
+        }
+        let display = window.display_handle()?.as_raw();
+        let window = window.window_handle()?.as_raw();
+        match (display, window) {
+            #[cfg(target_os = "linux")]
+            (RawDisplayHandle::Xlib(display), RawWindowHandle::Xlib(window)) => {
+                use x11_dl::xlib;
+
+                let xlib = self
+                    .xlib
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("Failed to load Xlib"))?;
+                let display = display
+                    .display
+                    .ok_or_else(|| anyhow::anyhow!("No X display"))?
+                    .as_ptr() as *mut xlib::Display;
+                // Safety: the display and window come from winit and outlive this call,
+                // which is made on the GUI thread that owns them.
+                unsafe {
+                    if grab {
+                        let status = (xlib.XGrabKeyboard)(
+                            display,
+                            window.window,
+                            xlib::True,
+                            xlib::GrabModeAsync,
+                            xlib::GrabModeAsync,
+                            xlib::CurrentTime,
+                        );
+                        if status != xlib::GrabSuccess {
+                            return Err(anyhow::anyhow!("X keyboard grab failed ({})", status));
+                        }
+                    } else {
+                        (xlib.XUngrabKeyboard)(display, xlib::CurrentTime);
+                    }
+                    (xlib.XFlush)(display);
+                }
+                self.grabbed = grab;
+                Ok(())
+            }
+            _ => Err(anyhow::anyhow!(
+                "Keyboard grab is only supported on X11 (display {:?})",
+                display
+            )),
+        }
+    }
+
+    /// Which modifier keys are down, by hand, or None where Xlib isn't available.
+    pub fn modifiers_down(&self, window: &impl HasDisplayHandle) -> Option<Vec<RDPModifier>> {
+        let display = window.display_handle().ok()?.as_raw();
+        match display {
+            #[cfg(target_os = "linux")]
+            RawDisplayHandle::Xlib(display) => {
+                use x11_dl::keysym;
+
+                let xlib = self.xlib.as_ref()?;
+                let display = display.display?.as_ptr() as *mut x11_dl::xlib::Display;
+                let keysyms = [
+                    (keysym::XK_Shift_L, RDPModifier::LeftShift),
+                    (keysym::XK_Shift_R, RDPModifier::RightShift),
+                    (keysym::XK_Control_L, RDPModifier::LeftCtrl),
+                    (keysym::XK_Control_R, RDPModifier::RightCtrl),
+                    (keysym::XK_Alt_L, RDPModifier::LeftAlt),
+                    (keysym::XK_Alt_R, RDPModifier::RightAlt),
+                    (keysym::XK_Super_L, RDPModifier::LeftMeta),
+                    (keysym::XK_Super_R, RDPModifier::RightMeta),
+                ];
+                let mut keymap = [0; 32];
+                // Safety: as for the grab; the keymap is the 32 bytes Xlib fills in.
+                unsafe {
+                    (xlib.XQueryKeymap)(display, keymap.as_mut_ptr());
+                    Some(
+                        keysyms
+                            .into_iter()
+                            .filter(|(keysym, _)| {
+                                let keycode =
+                                    usize::from((xlib.XKeysymToKeycode)(display, (*keysym).into()));
+                                // Zero means the keysym isn't on the keyboard at all.
+                                keycode != 0
+                                    && keymap[keycode / 8] as u8 & (1 << (keycode % 8)) != 0
+                            })
+                            .map(|(_, modifier)| modifier)
+                            .collect(),
+                    )
+                }
+            }
+            _ => None,
+        }
+    }
+}
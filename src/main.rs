@@ -1,10 +1,21 @@
 mod cli;
+mod config;
 mod gui;
+mod keyboard_grab;
+mod password;
 mod rdp;
-use clap::Parser;
+mod rdp_file;
 use eframe::egui;
 use ironrdp::pdu::input::fast_path::FastPathInputEvent;
-use rdp::{RDPCredentials, RDPMousePosition, RDPSession, RDPSharedFramebuffer};
+use rdp::cert::{RDPCertificatePolicy, RDPCertificatePrompt, RDPCertificateVerifier};
+use rdp::finalize::RDPServerInput;
+use rdp::gateway::RDPGateway;
+use rdp::layout::KeyboardLayout;
+use rdp::smartcard::RDPSmartCard;
+use rdp::{
+    RDPCredentials, RDPKerberos, RDPMousePosition, RDPPointerUpdate, RDPSession,
+    RDPSharedFramebuffer,
+};
 use std::sync::{Arc, Mutex};
 
 fn main() -> anyhow::Result<()> {
@@ -15,10 +26,102 @@ fn main() -> anyhow::Result<()> {
         .install_default()
         .expect("Failed to install rustls default provider");
 
-    let cli = cli::Cli::parse();
+    let cli = config::parse_cli();
+    if let Some(path) = &cli.export_rdp {
+        rdp_file::export(&cli, path)?;
+        log::info!("Saved connection settings to {}", path.display());
+        return Ok(());
+    }
+
+    let keyboard_layout = cli.keyboard_layout.or_else(KeyboardLayout::detect);
+    match keyboard_layout {
+        Some(layout) => log::info!("Using keyboard layout {:?}", layout),
+        None => log::info!("Using the server's default keyboard layout"),
+    }
 
-    let credentials = RDPCredentials::new(cli.username, cli.password, cli.domain);
-    let rdp = RDPSession::from_credentials(credentials).with_dynamic_channels(cli.dynamic_channels);
+    let resolution = cli.resolution;
+    let smart_card = match (&cli.smart_card_cert, &cli.smart_card_key) {
+        (Some(certificate), Some(key)) => Some(RDPSmartCard::from_pem_files(certificate, key)?),
+        _ => None,
+    };
+    // With a smart card, the password options give its PIN.
+    let secret = match &smart_card {
+        Some(card) => format!("Smart card PIN for {}", card.subject()),
+        None => format!(
+            "Password for {}",
+            cli.username.as_deref().unwrap_or_default()
+        ),
+    };
+    let password = if let Some(password) = cli.password {
+        Some(password)
+    } else if let Some(path) = &cli.password_file {
+        Some(password::read_file(path)?)
+    } else if cli.password_stdin {
+        Some(password::read_stdin(&format!("{}: ", secret))?)
+    } else {
+        None
+    };
+    // Without a password the GUI asks for one, and we wait for it before connecting.
+    let (password_tx, password_rx) = tokio::sync::oneshot::channel::<String>();
+    let login_prompt = match password {
+        Some(password) => {
+            let _ = password_tx.send(password);
+            None
+        }
+        None => Some(gui::LoginPrompt {
+            label: format!("{} on {}", secret, cli.host),
+            password_tx,
+        }),
+    };
+
+    // The gateway's certificate is checked like the server's, but has no fingerprint option.
+    let gateway_certificate_policy = if cli.ignore_cert {
+        RDPCertificatePolicy::Ignore
+    } else {
+        RDPCertificatePolicy::Verify
+    };
+    let certificate_policy = if cli.ignore_cert {
+        RDPCertificatePolicy::Ignore
+    } else if let Some(fingerprint) = cli.cert_fingerprint {
+        RDPCertificatePolicy::Fingerprint(fingerprint)
+    } else {
+        RDPCertificatePolicy::Verify
+    };
+    // Any of the Kerberos options turns it on.
+    let kerberos = RDPKerberos {
+        kdc_url: cli.kdc_url,
+        realm: cli.realm,
+        hostname: cli.kerberos_hostname,
+        timeout: std::time::Duration::from_secs(cli.kdc_timeout),
+        proxy_ca: cli.kdc_proxy_ca,
+        proxy_insecure: cli.kdc_proxy_insecure,
+    };
+    let kerberos =
+        (kerberos.kdc_url.is_some() || kerberos.realm.is_some() || kerberos.hostname.is_some())
+            .then_some(kerberos);
+    let known_hosts = config::config_dir().map(|dir| dir.join("known_hosts"));
+    let (certificate_prompt_tx, certificate_prompt_rx) =
+        tokio::sync::mpsc::unbounded_channel::<RDPCertificatePrompt>();
+
+    let (server_input_tx, server_input_rx) =
+        tokio::sync::watch::channel::<RDPServerInput>(Default::default());
+    let (session_end_tx, session_end_rx) = tokio::sync::oneshot::channel::<String>();
+
+    let options = gui::AppOptions {
+        relative_mouse: cli.relative_mouse,
+        server_input: server_input_rx,
+        keyboard_mode: cli.keyboard_mode,
+        keyboard_layout,
+        send_keys: cli.send_keys,
+        type_text: cli.type_text.unwrap_or_default(),
+        key_delay: std::time::Duration::from_millis(cli.key_delay),
+        macros: cli.macros,
+        grab_keyboard: cli.grab_keyboard,
+        release_key: cli.release_key,
+        login_prompt,
+        certificate_prompts: certificate_prompt_rx,
+        session_end: session_end_rx,
+    };
 
     // So we can pass a handle to the egui context back to the RDP thread,
     // allowing it to trigger a repaint when the view should update.
@@ -27,26 +130,90 @@ fn main() -> anyhow::Result<()> {
         tokio::sync::watch::channel::<Arc<Mutex<RDPSharedFramebuffer>>>(Default::default());
     let (mouse_tx, mouse_rx) = tokio::sync::watch::channel::<RDPMousePosition>(Default::default());
     let (rdp_input_tx, rdp_input_rx) = tokio::sync::mpsc::channel::<Vec<FastPathInputEvent>>(512);
+    let (pointer_tx, pointer_rx) = tokio::sync::mpsc::unbounded_channel::<RDPPointerUpdate>();
     // TODO handle error in initial thread creation.
     let rdp_session_thread = std::thread::spawn(move || {
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_io()
-            .build()
-            .unwrap();
-        // TODO actual RDP session error handling
-        let (connection_result, framed) = rt.block_on(rdp.connect(&cli.host, cli.port)).unwrap();
-        rt.block_on(RDPSession::session_thread(
-            framed,
-            connection_result,
-            tx,
-            mouse_rx,
-            rdp_input_rx,
-            rctx,
-        ))
+            .build()?;
+        let password = rt
+            .block_on(password_rx)
+            .map_err(|_| anyhow::anyhow!("No password was entered"))?;
+        let egui_ctx = rt.block_on(rctx)?;
+        // The gateway uses the host's credentials unless given its own.
+        let gateway = cli.gateway.map(|address| {
+            let password = match (&cli.gateway_password, &smart_card) {
+                (Some(password), _) => password.clone(),
+                (None, None) => password.clone(),
+                (None, Some(_)) => String::new(),
+            };
+            RDPGateway::new(address.host, address.port.unwrap_or(443))
+                .with_credentials(
+                    cli.gateway_username
+                        .or_else(|| cli.username.clone())
+                        .unwrap_or_default(),
+                    password,
+                    cli.gateway_domain.or_else(|| cli.domain.clone()),
+                )
+                .with_certificate_verifier(
+                    RDPCertificateVerifier::new(gateway_certificate_policy)
+                        .with_known_hosts(known_hosts.clone())
+                        .with_prompt(certificate_prompt_tx.clone(), egui_ctx.clone()),
+                )
+        });
+        let certificate_verifier = RDPCertificateVerifier::new(certificate_policy)
+            .with_known_hosts(known_hosts)
+            .with_prompt(certificate_prompt_tx, egui_ctx.clone());
+        let credentials = match smart_card {
+            Some(card) => RDPCredentials::smart_card(password, card, cli.domain),
+            None => RDPCredentials::new(cli.username.unwrap_or_default(), password, cli.domain),
+        };
+        let rdp = RDPSession::from_credentials(credentials)
+            .with_dynamic_channels(cli.dynamic_channels)
+            .with_resolution(resolution)
+            .with_performance_flags(&cli.performance_flags)
+            .with_software_pointer(cli.software_pointer)
+            .with_keyboard_layout(keyboard_layout)
+            .with_certificate_verifier(certificate_verifier)
+            .with_server_name(cli.server_name)
+            .with_security(cli.security)
+            .with_restricted_admin(cli.restricted_admin)
+            .with_gateway(gateway)
+            .with_proxy(cli.proxy)
+            .with_ssh_tunnel(cli.ssh_jump.map(|tunnel| tunnel.with_identity(cli.ssh_key)))
+            .with_kerberos(kerberos);
+        let result = rt.block_on(async {
+            let (connection_result, server_input, framed) = rdp
+                .connect(&cli.host.host, cli.host.port.unwrap_or(cli.port))
+                .await?;
+            let _ = server_input_tx.send(server_input);
+            RDPSession::session_thread(
+                framed,
+                connection_result,
+                tx,
+                mouse_rx,
+                rdp_input_rx,
+                pointer_tx,
+                egui_ctx.clone(),
+            )
+            .await
+        });
+        // Have the GUI show why the session ended, or close if it ended normally.
+        match &result {
+            Err(e) => {
+                let _ = session_end_tx.send(format!("{:#}", e));
+            }
+            Ok(()) => drop(session_end_tx),
+        }
+        egui_ctx.request_repaint();
+        result
     });
 
     let native_options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default().with_inner_size([1024.0, 768.0]),
+        viewport: egui::ViewportBuilder::default().with_inner_size([
+            f32::from(resolution.width),
+            f32::from(resolution.height) + gui::STATUS_BAR_HEIGHT,
+        ]),
         window_builder: Some(Box::new(|builder| builder.with_resizable(false))),
 
         ..Default::default()
@@ -61,7 +228,9 @@ fn main() -> anyhow::Result<()> {
                 rx,
                 mouse_tx,
                 rdp_input_tx,
+                pointer_rx,
                 tctx,
+                options,
             )))
         }),
     ) {
@@ -0,0 +1,105 @@
+use std::io::{BufRead, IsTerminal, Write};
+
+/// Read a password from the first line of a file, which should only be readable
+/// by its owner.
+pub fn read_file(path: &std::path::Path) -> anyhow::Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read password file {}: {}", path.display(), e))?;
+    Ok(contents.lines().next().unwrap_or_default().to_string())
+}
+
+/// Read a password from stdin, prompting without echo when it is a terminal.
+pub fn read_stdin(prompt: &str) -> anyhow::Result<String> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return read_line(&mut stdin.lock());
+    }
+
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+    let _echo = EchoOff::new(&stdin)?;
+    let password = read_line(&mut stdin.lock());
+    // The user's newline wasn't echoed either.
+    eprintln!();
+    password
+}
+
+fn read_line(reader: &mut impl BufRead) -> anyhow::Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Turns off terminal echo until dropped.
+#[cfg(unix)]
+struct EchoOff {
+    fd: std::os::fd::RawFd,
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl EchoOff {
+    fn new(stdin: &std::io::Stdin) -> std::io::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let fd = stdin.as_raw_fd();
+        // Safety: termios is plain data which tcgetattr fills in.
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let original = termios;
+            termios.c_lflag &= !libc::ECHO;
+            if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(Self { fd, original })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        // Safety: restores the settings read in new().
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct EchoOff;
+
+#[cfg(not(unix))]
+impl EchoOff {
+    fn new(_stdin: &std::io::Stdin) -> std::io::Result<Self> {
+        log::warn!("Can't turn off echo on this platform; the password will be visible");
+        Ok(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_line_of_file() {
+        let dir = std::env::temp_dir().join(format!("rcc-password-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let read = |contents: &str| {
+            let path = dir.join("password");
+            std::fs::write(&path, contents).unwrap();
+            read_file(&path).unwrap()
+        };
+
+        assert_eq!(read("secret\n"), "secret");
+        assert_eq!(read("secret\r\nignored\r\n"), "secret");
+        assert_eq!(read("secret"), "secret");
+        assert_eq!(read(" spaced out \n"), " spaced out ");
+        assert_eq!(read(""), "");
+        assert!(read_file(&dir.join("missing")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
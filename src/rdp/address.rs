@@ -0,0 +1,170 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+/// The server to connect to: `host`, `host:port`, `[ipv6]:port` or a bare IPv6 address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RDPServerAddress {
+    pub host: RDPHost,
+    pub port: Option<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RDPHost {
+    Ip(IpAddr),
+    Name(String),
+}
+
+impl RDPHost {
+    /// The host without IPv6 brackets, as used for the certificate check and the
+    /// CredSSP service principal name.
+    pub fn name(&self) -> String {
+        match self {
+            RDPHost::Ip(ip) => ip.to_string(),
+            RDPHost::Name(name) => name.clone(),
+        }
+    }
+}
+
+impl fmt::Display for RDPHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RDPHost::Ip(IpAddr::V6(ip)) => write!(f, "[{}]", ip),
+            RDPHost::Ip(IpAddr::V4(ip)) => write!(f, "{}", ip),
+            RDPHost::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl FromStr for RDPHost {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(ip) = s.parse() {
+            return Ok(RDPHost::Ip(ip));
+        }
+        let name = s.strip_suffix('.').unwrap_or(s);
+        let valid = !name.is_empty()
+            && name.split('.').all(|label| {
+                !label.is_empty()
+                    && !label.starts_with('-')
+                    && label
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+        if !valid {
+            return Err(anyhow!("'{}' is not a valid host name or IP address", s));
+        }
+        Ok(RDPHost::Name(name.to_string()))
+    }
+}
+
+impl fmt::Display for RDPServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}:{}", self.host, port),
+            None => write!(f, "{}", self.host),
+        }
+    }
+}
+
+impl FromStr for RDPServerAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (ip, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| anyhow!("Missing ']' in '{}'", s))?;
+            let ip: Ipv6Addr = ip
+                .parse()
+                .map_err(|_| anyhow!("'{}' is not an IPv6 address", ip))?;
+            let port = match rest {
+                "" => None,
+                rest => Some(rest.strip_prefix(':').ok_or_else(|| {
+                    anyhow!("Expected ':PORT' after the IPv6 address in '{}'", s)
+                })?),
+            };
+            (RDPHost::Ip(IpAddr::V6(ip)), port)
+        } else {
+            match s.split_once(':') {
+                // More than one colon is a bare IPv6 address, which can't have a port.
+                Some((_, rest)) if rest.contains(':') => (
+                    RDPHost::Ip(
+                        s.parse()
+                            .map_err(|_| anyhow!("'{}' is not an IPv6 address", s))?,
+                    ),
+                    None,
+                ),
+                Some((host, port)) => (host.parse()?, Some(port)),
+                None => (s.parse()?, None),
+            }
+        };
+        let port = port
+            .map(|port| {
+                port.parse::<u16>()
+                    .ok()
+                    .filter(|port| *port != 0)
+                    .ok_or_else(|| anyhow!("'{}' is not a valid port", port))
+            })
+            .transpose()?;
+        Ok(Self { host, port })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn parse(s: &str) -> (RDPHost, Option<u16>) {
+        let address: RDPServerAddress = s.parse().unwrap();
+        (address.host, address.port)
+    }
+
+    #[test]
+    fn address_forms() {
+        let v4 = RDPHost::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)));
+        let v6 = RDPHost::Ip(IpAddr::V6(Ipv6Addr::LOCALHOST));
+        let name = RDPHost::Name("vm1.lab.example".to_string());
+
+        assert_eq!(parse("10.0.0.5"), (v4.clone(), None));
+        assert_eq!(parse("10.0.0.5:3390"), (v4, Some(3390)));
+        assert_eq!(parse("[::1]:3390"), (v6.clone(), Some(3390)));
+        assert_eq!(parse("[::1]"), (v6.clone(), None));
+        assert_eq!(parse("::1"), (v6, None));
+        assert_eq!(parse("vm1.lab.example"), (name.clone(), None));
+        assert_eq!(parse("vm1.lab.example.:3390"), (name, Some(3390)));
+    }
+
+    #[test]
+    fn bad_addresses() {
+        for bad in [
+            "",
+            "vm1:",
+            "vm1:0",
+            "vm1:99999",
+            "vm 1",
+            "vm1/path",
+            "-vm1",
+            "[::1",
+            "[::1]3390",
+            "[10.0.0.5]",
+            "fe80::zz",
+        ] {
+            assert!(bad.parse::<RDPServerAddress>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn names_and_display() {
+        let address: RDPServerAddress = "[fe80::1]:3390".parse().unwrap();
+        assert_eq!(address.host.name(), "fe80::1");
+        assert_eq!(address.to_string(), "[fe80::1]:3390");
+        let address: RDPServerAddress = "10.0.0.5".parse().unwrap();
+        assert_eq!(address.host.name(), "10.0.0.5");
+        assert_eq!(address.to_string(), "10.0.0.5");
+    }
+}
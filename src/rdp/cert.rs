@@ -0,0 +1,287 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use eframe::egui;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use sha2::{Digest, Sha256};
+
+/// How to decide whether to trust the server's certificate.
+#[derive(Clone, Default, Debug)]
+pub enum RDPCertificatePolicy {
+    /// Trust certificates which chain to the system trust store and otherwise fall
+    /// back to the known hosts file, trusting a host's first certificate.
+    #[default]
+    Verify,
+    /// Trust anything.
+    Ignore,
+    /// Trust only the certificate with this SHA-256 fingerprint.
+    Fingerprint(String),
+}
+
+/// Asks the user whether to accept a certificate which isn't the one seen before.
+pub struct RDPCertificatePrompt {
+    pub host: String,
+    pub fingerprint: String,
+    pub known_fingerprint: String,
+    pub reply: tokio::sync::oneshot::Sender<bool>,
+}
+
+#[derive(Default)]
+pub struct RDPCertificateVerifier {
+    policy: RDPCertificatePolicy,
+    known_hosts: Option<PathBuf>,
+    prompt: Option<(
+        tokio::sync::mpsc::UnboundedSender<RDPCertificatePrompt>,
+        egui::Context,
+    )>,
+}
+
+impl RDPCertificateVerifier {
+    pub fn new(policy: RDPCertificatePolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_known_hosts(mut self, path: Option<PathBuf>) -> Self {
+        self.known_hosts = path;
+        self
+    }
+
+    /// Ask through the GUI when a known host's certificate has changed, rather
+    /// than refusing to connect.
+    pub fn with_prompt(
+        mut self,
+        prompt_tx: tokio::sync::mpsc::UnboundedSender<RDPCertificatePrompt>,
+        egui_ctx: egui::Context,
+    ) -> Self {
+        self.prompt = Some((prompt_tx, egui_ctx));
+        self
+    }
+
+    pub async fn verify(
+        &self,
+        host: &str,
+        port: u16,
+        chain: &[CertificateDer<'_>],
+    ) -> anyhow::Result<()> {
+        let end_entity = chain
+            .first()
+            .ok_or_else(|| anyhow!("Server sent no certificate"))?;
+        let fingerprint = fingerprint(end_entity);
+        match &self.policy {
+            RDPCertificatePolicy::Ignore => {
+                log::warn!("Not verifying server certificate {}", fingerprint);
+                return Ok(());
+            }
+            RDPCertificatePolicy::Fingerprint(expected) => {
+                return if same_fingerprint(expected, &fingerprint) {
+                    Ok(())
+                } else {
+                    Err(anyhow!(
+                        "Server certificate {} does not have the expected fingerprint",
+                        fingerprint
+                    ))
+                };
+            }
+            RDPCertificatePolicy::Verify => {}
+        }
+
+        let untrusted = match verify_chain(host, chain) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let key = format!("{}:{}", host, port);
+        let Some(path) = &self.known_hosts else {
+            return Err(anyhow!(
+                "Server certificate for {} is not trusted: {}",
+                key,
+                untrusted
+            ));
+        };
+
+        let mut known_hosts = KnownHosts::load(path)?;
+        match known_hosts.get(&key) {
+            Some(known) if same_fingerprint(known, &fingerprint) => return Ok(()),
+            Some(known) => {
+                let known = known.to_string();
+                if !self.ask(&key, &fingerprint, known).await {
+                    return Err(anyhow!(
+                        "Server certificate for {} has changed and was not accepted",
+                        key
+                    ));
+                }
+            }
+            None => log::warn!(
+                "Trusting certificate {} for {} on first use ({})",
+                fingerprint,
+                key,
+                untrusted
+            ),
+        }
+        known_hosts.set(&key, &fingerprint);
+        known_hosts.save(path)
+    }
+
+    async fn ask(&self, host: &str, fingerprint: &str, known_fingerprint: String) -> bool {
+        let Some((prompt_tx, egui_ctx)) = &self.prompt else {
+            return false;
+        };
+        let (reply, answer) = tokio::sync::oneshot::channel();
+        let prompt = RDPCertificatePrompt {
+            host: host.to_string(),
+            fingerprint: fingerprint.to_string(),
+            known_fingerprint,
+            reply,
+        };
+        if prompt_tx.send(prompt).is_err() {
+            return false;
+        }
+        egui_ctx.request_repaint();
+        answer.await.unwrap_or(false)
+    }
+}
+
+/// Check the chain against the system trust store and the host name.
+fn verify_chain(host: &str, chain: &[CertificateDer<'_>]) -> anyhow::Result<()> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    use rustls::client::danger::ServerCertVerifier;
+
+    let verifier = rustls::client::WebPkiServerVerifier::builder_with_provider(
+        Arc::new(roots),
+        Arc::new(rustls::crypto::ring::default_provider()),
+    )
+    .build()?;
+    let name = ServerName::try_from(host.to_string())?;
+    verifier.verify_server_cert(&chain[0], &chain[1..], &name, &[], UnixTime::now())?;
+    Ok(())
+}
+
+/// SHA-256 of the certificate, formatted as colon separated hex as browsers show it.
+pub fn fingerprint(certificate: &CertificateDer<'_>) -> String {
+    Sha256::digest(certificate.as_ref())
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Compare fingerprints ignoring case, separators and a `sha256:` prefix.
+fn same_fingerprint(a: &str, b: &str) -> bool {
+    let normalize = |s: &str| {
+        let s = s.trim();
+        let s = s
+            .get(..7)
+            .filter(|prefix| prefix.eq_ignore_ascii_case("sha256:"))
+            .map_or(s, |_| &s[7..]);
+        s.chars()
+            .filter(char::is_ascii_hexdigit)
+            .map(|c| c.to_ascii_lowercase())
+            .collect::<String>()
+    };
+    normalize(a) == normalize(b)
+}
+
+/// Certificate fingerprints seen before, one `host:port fingerprint` per line.
+struct KnownHosts {
+    entries: Vec<(String, String)>,
+}
+
+impl KnownHosts {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(anyhow!("Failed to read {}: {}", path.display(), e)),
+        };
+        let entries = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(char::is_whitespace))
+            .map(|(host, fingerprint)| (host.to_string(), fingerprint.trim().to_string()))
+            .collect();
+        Ok(Self { entries })
+    }
+
+    fn get(&self, host: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(h, _)| h == host)
+            .map(|(_, fingerprint)| fingerprint.as_str())
+    }
+
+    fn set(&mut self, host: &str, fingerprint: &str) {
+        self.entries.retain(|(h, _)| h != host);
+        self.entries
+            .push((host.to_string(), fingerprint.to_string()));
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text: String = self
+            .entries
+            .iter()
+            .map(|(host, fingerprint)| format!("{} {}\n", host, fingerprint))
+            .collect();
+        std::fs::write(path, text).map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints_compare_loosely() {
+        assert!(same_fingerprint("AB:CD:01", "abcd01"));
+        assert!(same_fingerprint("sha256:abcd01", "AB:CD:01"));
+        assert!(!same_fingerprint("AB:CD:02", "AB:CD:01"));
+    }
+
+    #[tokio::test]
+    async fn trust_on_first_use() {
+        let path = std::env::temp_dir().join(format!("rcc-known-hosts-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let verifier = RDPCertificateVerifier::new(RDPCertificatePolicy::Verify)
+            .with_known_hosts(Some(path.clone()));
+        // Not a real certificate, so it never chains to a trusted root.
+        let first = [CertificateDer::from(vec![1, 2, 3])];
+        let second = [CertificateDer::from(vec![4, 5, 6])];
+
+        verifier.verify("vm1", 3389, &first).await.unwrap();
+        verifier.verify("vm1", 3389, &first).await.unwrap();
+        // Changed, and there's no GUI to ask.
+        assert!(verifier.verify("vm1", 3389, &second).await.is_err());
+        // Kept per port.
+        verifier.verify("vm1", 3390, &second).await.unwrap();
+
+        let known_hosts = KnownHosts::load(&path).unwrap();
+        assert_eq!(
+            known_hosts.get("vm1:3389"),
+            Some(fingerprint(&first[0]).as_str())
+        );
+        assert_eq!(
+            known_hosts.get("vm1:3390"),
+            Some(fingerprint(&second[0]).as_str())
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pinned_fingerprint() {
+        let cert = CertificateDer::from(vec![1, 2, 3]);
+        let pinned =
+            RDPCertificateVerifier::new(RDPCertificatePolicy::Fingerprint(fingerprint(&cert)));
+        pinned.verify("vm1", 3389, &[cert]).await.unwrap();
+        assert!(pinned
+            .verify("vm1", 3389, &[CertificateDer::from(vec![4])])
+            .await
+            .is_err());
+    }
+}
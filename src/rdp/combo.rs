@@ -0,0 +1,217 @@
+use std::str::FromStr;
+
+use ironrdp::pdu::input::fast_path::FastPathInputEvent;
+
+use super::scancode::PhysicalKey;
+
+/// Keys which are pressed together, e.g. `Ctrl+Alt+Delete`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KeyCombo {
+    keys: Vec<PhysicalKey>,
+}
+
+/// Combinations which the local desktop usually intercepts, offered in the GUI.
+pub static SPECIAL_KEY_COMBOS: &[(&str, &[PhysicalKey])] = &[
+    (
+        "Ctrl+Alt+Del",
+        &[
+            PhysicalKey::ControlLeft,
+            PhysicalKey::AltLeft,
+            PhysicalKey::Delete,
+        ],
+    ),
+    (
+        "Ctrl+Shift+Esc",
+        &[
+            PhysicalKey::ControlLeft,
+            PhysicalKey::ShiftLeft,
+            PhysicalKey::Escape,
+        ],
+    ),
+    ("Ctrl+Esc", &[PhysicalKey::ControlLeft, PhysicalKey::Escape]),
+    ("Alt+Tab", &[PhysicalKey::AltLeft, PhysicalKey::Tab]),
+    ("Alt+F4", &[PhysicalKey::AltLeft, PhysicalKey::F4]),
+    ("Win", &[PhysicalKey::MetaLeft]),
+    ("Win+L", &[PhysicalKey::MetaLeft, PhysicalKey::KeyL]),
+    ("Win+R", &[PhysicalKey::MetaLeft, PhysicalKey::KeyR]),
+    ("Win+D", &[PhysicalKey::MetaLeft, PhysicalKey::KeyD]),
+    ("Print Screen", &[PhysicalKey::PrintScreen]),
+    (
+        "Alt+Print Screen",
+        &[PhysicalKey::AltLeft, PhysicalKey::PrintScreen],
+    ),
+];
+
+impl KeyCombo {
+    pub fn new(keys: &[PhysicalKey]) -> Self {
+        Self {
+            keys: keys.to_vec(),
+        }
+    }
+
+    /// Press each key in turn, then release them in reverse order, as a person would.
+    pub fn events(&self) -> Vec<FastPathInputEvent> {
+        let presses = self.keys.iter().flat_map(|key| key.scancode().events(true));
+        let releases = self
+            .keys
+            .iter()
+            .rev()
+            .flat_map(|key| key.scancode().events(false));
+        presses.chain(releases).collect()
+    }
+}
+
+impl FromStr for KeyCombo {
+    type Err = anyhow::Error;
+
+    /// Parse keys joined by `+`, each being a key code name such as `Delete`, a
+    /// single letter or digit, or a common abbreviation such as `ctrl` or `win`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let keys = s
+            .split('+')
+            .map(|key| parse_key(key.trim()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { keys })
+    }
+}
+
+fn parse_key(name: &str) -> anyhow::Result<PhysicalKey> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if c.is_ascii_alphabetic() {
+            return format!("Key{}", c).parse();
+        } else if c.is_ascii_digit() {
+            return format!("Digit{}", c).parse();
+        }
+    }
+
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "ctrl" | "control" => PhysicalKey::ControlLeft,
+        "shift" => PhysicalKey::ShiftLeft,
+        "alt" => PhysicalKey::AltLeft,
+        "altgr" => PhysicalKey::AltRight,
+        "win" | "super" | "meta" | "cmd" => PhysicalKey::MetaLeft,
+        "del" => PhysicalKey::Delete,
+        "ins" => PhysicalKey::Insert,
+        "esc" => PhysicalKey::Escape,
+        "return" => PhysicalKey::Enter,
+        "pgup" => PhysicalKey::PageUp,
+        "pgdn" => PhysicalKey::PageDown,
+        "prtsc" => PhysicalKey::PrintScreen,
+        "menu" => PhysicalKey::ContextMenu,
+        "up" => PhysicalKey::ArrowUp,
+        "down" => PhysicalKey::ArrowDown,
+        "left" => PhysicalKey::ArrowLeft,
+        "right" => PhysicalKey::ArrowRight,
+        _ => return name.parse(),
+    })
+}
+
+/// Combinations sent one after another, written separated by spaces, e.g.
+/// `Win+R Esc`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KeySequence {
+    combos: Vec<KeyCombo>,
+}
+
+impl KeySequence {
+    pub fn events(&self) -> Vec<FastPathInputEvent> {
+        self.combos.iter().flat_map(KeyCombo::events).collect()
+    }
+}
+
+impl FromStr for KeySequence {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let combos = s
+            .split_whitespace()
+            .map(KeyCombo::from_str)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if combos.is_empty() {
+            return Err(anyhow::anyhow!("Empty key sequence"));
+        }
+        Ok(Self { combos })
+    }
+}
+
+/// A named key sequence to offer in the GUI, written as `NAME=SEQUENCE`.
+#[derive(Clone, Debug)]
+pub struct KeyMacro {
+    pub name: String,
+    pub sequence: KeySequence,
+}
+
+impl FromStr for KeyMacro {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, sequence) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected NAME=SEQUENCE, got '{}'", s))?;
+        Ok(Self {
+            name: name.trim().to_string(),
+            sequence: sequence.parse()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_combos() {
+        let cases = [
+            (
+                "ctrl+alt+del",
+                vec![
+                    PhysicalKey::ControlLeft,
+                    PhysicalKey::AltLeft,
+                    PhysicalKey::Delete,
+                ],
+            ),
+            ("Win+L", vec![PhysicalKey::MetaLeft, PhysicalKey::KeyL]),
+            ("shift+F10", vec![PhysicalKey::ShiftLeft, PhysicalKey::F10]),
+            ("NumpadEnter", vec![PhysicalKey::NumpadEnter]),
+            ("alt + 4", vec![PhysicalKey::AltLeft, PhysicalKey::Digit4]),
+        ];
+        for (s, keys) in cases {
+            assert_eq!(s.parse::<KeyCombo>().unwrap(), KeyCombo { keys }, "{}", s);
+        }
+        assert!("ctrl+nosuchkey".parse::<KeyCombo>().is_err());
+        assert!("".parse::<KeySequence>().is_err());
+    }
+
+    #[test]
+    fn combo_releases_in_reverse() {
+        let combo: KeyCombo = "ctrl+alt+del".parse().unwrap();
+        assert_eq!(
+            combo.events(),
+            [
+                PhysicalKey::ControlLeft.scancode().events(true),
+                PhysicalKey::AltLeft.scancode().events(true),
+                PhysicalKey::Delete.scancode().events(true),
+                PhysicalKey::Delete.scancode().events(false),
+                PhysicalKey::AltLeft.scancode().events(false),
+                PhysicalKey::ControlLeft.scancode().events(false),
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn parse_macro() {
+        let m: KeyMacro = "Run box = win+r esc".parse().unwrap();
+        assert_eq!(m.name, "Run box");
+        assert_eq!(
+            m.sequence.events(),
+            [
+                KeyCombo::new(&[PhysicalKey::MetaLeft, PhysicalKey::KeyR]).events(),
+                KeyCombo::new(&[PhysicalKey::Escape]).events(),
+            ]
+            .concat()
+        );
+        assert!("win+r".parse::<KeyMacro>().is_err());
+    }
+}
@@ -0,0 +1,227 @@
+//! The rest of the connection sequence after the TLS upgrade. IronRDP's connector
+//! keeps the server's capabilities to itself, so this runs the sequence the way
+//! `ironrdp_tokio::connect_finalize` does while looking at the Demand Active PDU.
+
+use ironrdp::connector::{
+    credssp::{CredsspSequence, KerberosConfig},
+    custom_err, general_err, legacy, ClientConnector, ClientConnectorState, ConnectionResult,
+    ConnectorErrorKind, ConnectorResult, Sequence, ServerName, State, Written,
+};
+use ironrdp::pdu::rdp::capability_sets::{CapabilitySet, InputFlags};
+use ironrdp::pdu::rdp::headers::ShareControlPdu;
+use ironrdp::pdu::PduHint;
+use ironrdp_core::WriteBuf;
+use ironrdp_tokio::{AsyncNetworkClient, Framed, FramedRead, FramedWrite, Upgraded};
+use sspi::generator::GeneratorState;
+
+/// What the server said it accepts in the way of input.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RDPServerInput {
+    /// Relative mouse events (TS_FP_RELPOINTER_EVENT).
+    pub relative_mouse: bool,
+}
+
+impl RDPServerInput {
+    fn from_capabilities(capability_sets: &[CapabilitySet]) -> Self {
+        let input_flags = capability_sets
+            .iter()
+            .find_map(|c| match c {
+                CapabilitySet::Input(input) => Some(input.input_flags),
+                _ => None,
+            })
+            .unwrap_or(InputFlags::empty());
+        Self {
+            relative_mouse: input_flags.contains(InputFlags::MOUSE_RELATIVE),
+        }
+    }
+}
+
+/// Passes PDUs on to the connector, noting the server's input capabilities.
+struct WatchedConnector<'a> {
+    connector: &'a mut ClientConnector,
+    server_input: Option<RDPServerInput>,
+}
+
+impl WatchedConnector<'_> {
+    fn watch(&mut self, pdu: &[u8]) {
+        if self.server_input.is_some() {
+            return;
+        }
+        // Anything else on the way, or which fails to decode, is the connector's business.
+        let Ok(ctx) = legacy::decode_send_data_indication(pdu) else {
+            return;
+        };
+        if let Ok(ctx) = legacy::decode_share_control(ctx) {
+            if let ShareControlPdu::ServerDemandActive(demand_active) = ctx.pdu {
+                self.server_input = Some(RDPServerInput::from_capabilities(
+                    &demand_active.pdu.capability_sets,
+                ));
+            }
+        }
+    }
+}
+
+impl Sequence for WatchedConnector<'_> {
+    fn next_pdu_hint(&self) -> Option<&dyn PduHint> {
+        self.connector.next_pdu_hint()
+    }
+
+    fn state(&self) -> &dyn State {
+        self.connector.state()
+    }
+
+    fn step(&mut self, input: &[u8], output: &mut WriteBuf) -> ConnectorResult<Written> {
+        self.watch(input);
+        self.connector.step(input, output)
+    }
+
+    fn step_no_input(&mut self, output: &mut WriteBuf) -> ConnectorResult<Written> {
+        self.connector.step_no_input(output)
+    }
+}
+
+/// Finish connecting, as `ironrdp_tokio::connect_finalize` would, also returning
+/// what input the server accepts.
+pub(crate) async fn connect_finalize<S>(
+    _: Upgraded,
+    framed: &mut Framed<S>,
+    mut connector: ClientConnector,
+    server_name: ServerName,
+    server_public_key: Vec<u8>,
+    network_client: &mut dyn AsyncNetworkClient,
+    kerberos_config: Option<KerberosConfig>,
+) -> ConnectorResult<(ConnectionResult, RDPServerInput)>
+where
+    S: FramedRead + FramedWrite,
+{
+    if connector.should_perform_credssp() {
+        perform_credssp(
+            framed,
+            &mut connector,
+            server_name,
+            server_public_key,
+            network_client,
+            kerberos_config,
+        )
+        .await?;
+    }
+
+    let mut buf = WriteBuf::new();
+    let mut watched = WatchedConnector {
+        connector: &mut connector,
+        server_input: None,
+    };
+    loop {
+        ironrdp_tokio::single_sequence_step(framed, &mut watched, &mut buf).await?;
+        if let ClientConnectorState::Connected { .. } = watched.connector.state {
+            break;
+        }
+    }
+    let server_input = watched.server_input.unwrap_or_default();
+    match connector.state {
+        ClientConnectorState::Connected { result } => Ok((result, server_input)),
+        _ => Err(general_err!("connection sequence ended without connecting")),
+    }
+}
+
+async fn perform_credssp<S>(
+    framed: &mut Framed<S>,
+    connector: &mut ClientConnector,
+    server_name: ServerName,
+    server_public_key: Vec<u8>,
+    network_client: &mut dyn AsyncNetworkClient,
+    kerberos_config: Option<KerberosConfig>,
+) -> ConnectorResult<()>
+where
+    S: FramedRead + FramedWrite,
+{
+    let ClientConnectorState::Credssp { selected_protocol } = connector.state else {
+        return Err(general_err!("invalid connector state for CredSSP sequence"));
+    };
+    let (mut sequence, mut ts_request) = CredsspSequence::init(
+        connector.config.credentials.clone(),
+        connector.config.domain.as_deref(),
+        selected_protocol,
+        server_name,
+        server_public_key,
+        kerberos_config,
+    )?;
+
+    let mut buf = WriteBuf::new();
+    loop {
+        let client_state = {
+            let mut generator = sequence.process_ts_request(ts_request);
+            let mut state = generator.start();
+            loop {
+                match state {
+                    GeneratorState::Suspended(request) => {
+                        let response = network_client.send(&request).await?;
+                        state = generator.resume(Ok(response));
+                    }
+                    GeneratorState::Completed(client_state) => {
+                        break client_state.map_err(|e| {
+                            ironrdp::connector::ConnectorError::new(
+                                "CredSSP",
+                                ConnectorErrorKind::Credssp(e),
+                            )
+                        })?
+                    }
+                }
+            }
+        };
+
+        buf.clear();
+        let written = sequence.handle_process_result(client_state, &mut buf)?;
+        if let Some(response_len) = written.size() {
+            framed
+                .write_all(&buf[..response_len])
+                .await
+                .map_err(|e| custom_err!("write all", e))?;
+        }
+
+        let Some(next_pdu_hint) = sequence.next_pdu_hint() else {
+            break;
+        };
+        let pdu = framed
+            .read_by_hint(next_pdu_hint)
+            .await
+            .map_err(|e| custom_err!("read frame by hint", e))?;
+        match sequence.decode_server_message(&pdu)? {
+            Some(next_request) => ts_request = next_request,
+            None => break,
+        }
+    }
+
+    connector.mark_credssp_as_done();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ironrdp::pdu::rdp::capability_sets::Input;
+
+    #[test]
+    fn relative_mouse_from_input_capability() {
+        let input = |input_flags| {
+            CapabilitySet::Input(Input {
+                input_flags,
+                keyboard_layout: 0,
+                keyboard_type: None,
+                keyboard_subtype: 0,
+                keyboard_function_key: 0,
+                keyboard_ime_filename: String::new(),
+            })
+        };
+        let relative = RDPServerInput::from_capabilities(&[input(
+            InputFlags::SCANCODES | InputFlags::MOUSE_RELATIVE,
+        )]);
+        assert!(relative.relative_mouse);
+        let absolute = RDPServerInput::from_capabilities(&[input(InputFlags::SCANCODES)]);
+        assert!(!absolute.relative_mouse);
+        assert_eq!(
+            RDPServerInput::from_capabilities(&[]),
+            RDPServerInput::default()
+        );
+    }
+}
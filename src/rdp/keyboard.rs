@@ -1,144 +1,560 @@
-use eframe::egui::{Event, Key};
-use ironrdp::pdu::input::fast_path::{FastPathInputEvent, KeyboardFlags};
+use eframe::egui::{Event, ImeEvent, Key, Modifiers};
+use ironrdp::pdu::input::fast_path::{FastPathInputEvent, KeyboardFlags, SynchronizeFlags};
+
+use super::layout::{KeyLevel, KeyboardLayout};
+use super::scancode::PhysicalKey;
+
+/// How typed characters are sent to the server.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum RDPKeyboardMode {
+    /// Physical key scan codes, interpreted by the server's keyboard layout.
+    #[default]
+    Scancode,
+    /// Characters from the local layout or IME; other keys and shortcuts still use scan codes.
+    Unicode,
+}
 
 pub struct RDPKeyboardEvents {
     fastpath_events: Vec<FastPathInputEvent>,
 }
 
 impl RDPKeyboardEvents {
-    pub fn maybe_from(event: &Event) -> Option<RDPKeyboardEvents> {
-        match event {
+    pub fn into_fastpath_events(self) -> Vec<FastPathInputEvent> {
+        self.fastpath_events
+    }
+}
+
+/// Modifier keys, distinguishing the left and right hand variants since the
+/// server may treat them differently (e.g. right Alt as AltGr).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RDPModifier {
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    RightAlt,
+    LeftMeta,
+    RightMeta,
+}
+
+impl RDPModifier {
+    pub fn physical_key(self) -> PhysicalKey {
+        match self {
+            RDPModifier::LeftShift => PhysicalKey::ShiftLeft,
+            RDPModifier::RightShift => PhysicalKey::ShiftRight,
+            RDPModifier::LeftCtrl => PhysicalKey::ControlLeft,
+            RDPModifier::RightCtrl => PhysicalKey::ControlRight,
+            RDPModifier::LeftAlt => PhysicalKey::AltLeft,
+            RDPModifier::RightAlt => PhysicalKey::AltRight,
+            RDPModifier::LeftMeta => PhysicalKey::MetaLeft,
+            RDPModifier::RightMeta => PhysicalKey::MetaRight,
+        }
+    }
+}
+
+/// The state of the toggle keys, as carried by a synchronize event.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RDPLockKeys {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+    pub kana_lock: bool,
+}
+
+impl RDPLockKeys {
+    /// Read the local lock state from the keyboard LEDs. egui doesn't report lock
+    /// keys at all, so this is only available on Linux.
+    pub fn local() -> Option<Self> {
+        let mut lock_keys = Self::default();
+        let mut found = false;
+        for entry in std::fs::read_dir("/sys/class/leds").ok()?.flatten() {
+            // e.g. input3::capslock, one set per keyboard.
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let lock = match name.rsplit_once("::").map(|(_, led)| led) {
+                Some("capslock") => &mut lock_keys.caps_lock,
+                Some("numlock") => &mut lock_keys.num_lock,
+                Some("scrolllock") => &mut lock_keys.scroll_lock,
+                Some("kana") => &mut lock_keys.kana_lock,
+                _ => continue,
+            };
+            found = true;
+            *lock |= std::fs::read_to_string(entry.path().join("brightness"))
+                .is_ok_and(|brightness| brightness.trim() != "0");
+        }
+        found.then_some(lock_keys)
+    }
+
+    pub fn sync_event(self) -> FastPathInputEvent {
+        let mut flags = SynchronizeFlags::empty();
+        flags.set(SynchronizeFlags::CAPS_LOCK, self.caps_lock);
+        flags.set(SynchronizeFlags::NUM_LOCK, self.num_lock);
+        flags.set(SynchronizeFlags::SCROLL_LOCK, self.scroll_lock);
+        flags.set(SynchronizeFlags::KANA_LOCK, self.kana_lock);
+        FastPathInputEvent::SyncEvent(flags)
+    }
+}
+
+/// Tracks which keys we have told the server are down, so that only changes
+/// are sent and everything can be released again when focus is lost.
+#[derive(Default)]
+pub struct RDPKeyboardState {
+    mode: RDPKeyboardMode,
+    modifiers_down: Vec<RDPModifier>,
+    keys_down: Vec<PhysicalKey>,
+    /// egui swallows the key press behind copy, cut and paste shortcuts, so
+    /// we send the whole keystroke once the release turns up.
+    clipboard_shortcut_pending: bool,
+    /// Used to type characters whose key egui can't identify, e.g. the extra key
+    /// beside left shift on ISO keyboards.
+    layout: Option<KeyboardLayout>,
+    /// Set when a text key went out as a scan code, so the Event::Text which
+    /// follows it is not typed a second time. egui sends that straight after the
+    /// key press, so any other key event clears it.
+    text_expected: bool,
+    /// The lock state most recently sent to the server, if any.
+    lock_keys: Option<RDPLockKeys>,
+    /// Which modifier keys are down locally, by hand, where the platform can say.
+    local_modifiers: Option<Vec<RDPModifier>>,
+}
+
+impl RDPKeyboardState {
+    pub fn new(mode: RDPKeyboardMode, layout: Option<KeyboardLayout>) -> Self {
+        Self {
+            mode,
+            layout,
+            ..Default::default()
+        }
+    }
+
+    pub fn mode(&self) -> RDPKeyboardMode {
+        self.mode
+    }
+
+    pub fn lock_keys(&self) -> Option<RDPLockKeys> {
+        self.lock_keys
+    }
+
+    /// Tell the server about the lock state if it has changed, or regardless when
+    /// `force` is set (e.g. on regaining focus, when the server may have changed it).
+    pub fn sync_lock_keys(
+        &mut self,
+        lock_keys: RDPLockKeys,
+        force: bool,
+    ) -> Option<Vec<FastPathInputEvent>> {
+        if !force && self.lock_keys == Some(lock_keys) {
+            return None;
+        }
+        self.lock_keys = Some(lock_keys);
+        Some(vec![lock_keys.sync_event()])
+    }
+
+    pub fn maybe_from(&mut self, event: &Event) -> Option<RDPKeyboardEvents> {
+        let fastpath_events = match event {
             Event::Key {
                 key,
                 physical_key,
                 pressed,
-                repeat,
+                repeat: _,
                 modifiers,
             } => {
-                let mut fastpath_events = Vec::<FastPathInputEvent>::default();
-
-                // TODO process modifiers
-                let r = get_scancode(physical_key.as_ref().unwrap_or(key));
-                if let Err(e) = r {
-                    log::error!("Key error: {}", e);
-                    return None;
-                } else {
-                    let mut flags = if *pressed {
-                        KeyboardFlags::empty()
-                    } else {
-                        KeyboardFlags::RELEASE
+                let physical_key =
+                    match physical_key_from_egui(physical_key.as_ref().unwrap_or(key)) {
+                        Ok(physical_key) => physical_key,
+                        Err(e) => {
+                            log::error!("Key error: {}", e);
+                            return None;
+                        }
                     };
 
-                    // TODO instead of sending modifiers on every keystroke, keep state and
-                    // only send required deltas.
-                    if modifiers.alt {
-                        fastpath_events.push(FastPathInputEvent::KeyboardEvent(
-                            flags,
-                            SCANCODE_MODIFIER_ALT,
-                        ));
-                    }
-                    if modifiers.shift {
-                        fastpath_events.push(FastPathInputEvent::KeyboardEvent(
-                            flags,
-                            SCANCODE_MODIFIER_SHIFT,
-                        ));
-                    }
-                    if modifiers.ctrl {
-                        fastpath_events.push(FastPathInputEvent::KeyboardEvent(
-                            flags,
-                            SCANCODE_MODIFIER_CTRL,
-                        ));
+                self.text_expected = false;
+                let mut fastpath_events = self.sync_modifiers(modifiers);
+                let is_shortcut = modifiers.ctrl || modifiers.alt || modifiers.mac_cmd;
+                if !*pressed && self.keys_down.contains(&physical_key) {
+                    // Released before anything else so a key sent as a shortcut isn't
+                    // left down when the modifier is let go first.
+                    self.keys_down.retain(|k| *k != physical_key);
+                    fastpath_events.extend(physical_key.scancode().events(false));
+                } else if self.mode == RDPKeyboardMode::Unicode && is_text_key(key) && !is_shortcut
+                {
+                    // The character arrives separately as an Event::Text.
+                } else if *pressed {
+                    self.text_expected = is_text_key(key) && !is_shortcut;
+                    // Held keys are sent again as the local auto-repeat fires, which is
+                    // how the server expects typematic repeat to arrive.
+                    if !self.keys_down.contains(&physical_key) {
+                        self.keys_down.push(physical_key);
                     }
+                    fastpath_events.extend(physical_key.scancode().events(true));
+                } else if self.clipboard_shortcut_pending {
+                    self.clipboard_shortcut_pending = false;
+                    fastpath_events.extend(physical_key.scancode().events(true));
+                    fastpath_events.extend(physical_key.scancode().events(false));
+                }
+                fastpath_events
+            }
+            Event::Copy | Event::Cut | Event::Paste(_) => {
+                self.clipboard_shortcut_pending = true;
+                return None;
+            }
+            Event::Text(text) | Event::Ime(ImeEvent::Commit(text))
+                if self.mode == RDPKeyboardMode::Unicode =>
+            {
+                unicode_events(text)
+            }
+            Event::Text(text) => {
+                if std::mem::take(&mut self.text_expected) {
+                    return None;
+                }
+                text.chars()
+                    .flat_map(|c| self.type_char(c).unwrap_or_default())
+                    .collect()
+            }
+            Event::WindowFocused(false) => self.release_all(),
+            _unsupported => return None,
+        };
 
-                    let (extended, scancode) = r.unwrap();
-                    if extended {
-                        flags |= KeyboardFlags::EXTENDED;
-                    }
+        if fastpath_events.is_empty() {
+            None
+        } else {
+            Some(RDPKeyboardEvents { fastpath_events })
+        }
+    }
 
-                    if *pressed {
-                        fastpath_events.push(FastPathInputEvent::KeyboardEvent(flags, scancode));
-                    } else {
-                        fastpath_events
-                            .insert(0, FastPathInputEvent::KeyboardEvent(flags, scancode));
-                    }
+    /// Note which modifier keys are down locally, or None if that isn't known.
+    pub fn set_local_modifiers(&mut self, local_modifiers: Option<Vec<RDPModifier>>) {
+        self.local_modifiers = local_modifiers;
+    }
+
+    /// Bring the remote modifier state in line with the local one, sending only the
+    /// changes. egui reports modifiers without left/right distinction, and the Windows
+    /// key only on macOS, so hands come from the local modifiers where known. Failing
+    /// that we press the left hand key when one is missing and leave either hand alone
+    /// when it is already down.
+    pub fn sync_modifiers(&mut self, modifiers: &Modifiers) -> Vec<FastPathInputEvent> {
+        let local_modifiers = self.local_modifiers.clone().unwrap_or_default();
+        let local_down = |modifier| local_modifiers.contains(&modifier);
+        let meta = modifiers.mac_cmd
+            || local_down(RDPModifier::LeftMeta)
+            || local_down(RDPModifier::RightMeta);
+        let wanted = [
+            (
+                modifiers.ctrl,
+                RDPModifier::LeftCtrl,
+                RDPModifier::RightCtrl,
+            ),
+            (modifiers.alt, RDPModifier::LeftAlt, RDPModifier::RightAlt),
+            (
+                modifiers.shift,
+                RDPModifier::LeftShift,
+                RDPModifier::RightShift,
+            ),
+            (meta, RDPModifier::LeftMeta, RDPModifier::RightMeta),
+        ];
+
+        let mut fastpath_events = Vec::new();
+        for (down, left, right) in wanted {
+            let local: Vec<RDPModifier> = [left, right]
+                .into_iter()
+                .filter(|m| local_down(*m))
+                .collect();
+            let held: Vec<RDPModifier> = [left, right]
+                .into_iter()
+                .filter(|m| self.modifiers_down.contains(m))
+                .collect();
+            let sides = match (down, local.is_empty(), held.is_empty()) {
+                (false, _, _) => Vec::new(),
+                (true, false, _) => local,
+                (true, true, false) => held,
+                (true, true, true) => vec![left],
+            };
+            for modifier in [left, right] {
+                if !sides.contains(&modifier) {
+                    fastpath_events.extend(self.release_modifier(modifier).into_iter().flatten());
                 }
+            }
+            for modifier in sides {
+                fastpath_events.extend(self.press_modifier(modifier).into_iter().flatten());
+            }
+        }
+        fastpath_events
+    }
+
+    pub fn press_modifier(&mut self, modifier: RDPModifier) -> Option<Vec<FastPathInputEvent>> {
+        if self.modifiers_down.contains(&modifier) {
+            return None;
+        }
+        self.modifiers_down.push(modifier);
+        Some(modifier.physical_key().scancode().events(true))
+    }
+
+    pub fn release_modifier(&mut self, modifier: RDPModifier) -> Option<Vec<FastPathInputEvent>> {
+        if !self.modifiers_down.contains(&modifier) {
+            return None;
+        }
+        self.modifiers_down.retain(|m| *m != modifier);
+        Some(modifier.physical_key().scancode().events(false))
+    }
+
+    /// Type `c` with the keys that produce it in the server's layout, pressing or
+    /// lifting shift and AltGr around it as needed and then restoring them.
+    pub fn type_char(&self, c: char) -> Option<Vec<FastPathInputEvent>> {
+        let (key, level) = self.layout?.keystroke(c)?;
 
-                Some(Self { fastpath_events })
+        let shifts: Vec<PhysicalKey> = self
+            .modifiers_down
+            .iter()
+            .filter(|m| matches!(m, RDPModifier::LeftShift | RDPModifier::RightShift))
+            .map(|m| m.physical_key())
+            .collect();
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+        if level == KeyLevel::Shift && shifts.is_empty() {
+            before.extend(PhysicalKey::ShiftLeft.scancode().events(true));
+            after.extend(PhysicalKey::ShiftLeft.scancode().events(false));
+        } else if level != KeyLevel::Shift {
+            for shift in shifts {
+                before.extend(shift.scancode().events(false));
+                after.extend(shift.scancode().events(true));
             }
-            _unsupported => None,
         }
+        if level == KeyLevel::AltGr && !self.modifiers_down.contains(&RDPModifier::RightAlt) {
+            before.extend(PhysicalKey::AltRight.scancode().events(true));
+            after.splice(0..0, PhysicalKey::AltRight.scancode().events(false));
+        }
+
+        before.extend(key.scancode().events(true));
+        before.extend(key.scancode().events(false));
+        before.extend(after);
+        Some(before)
     }
 
-    pub fn as_fastpath_events(self) -> Vec<FastPathInputEvent> {
-        self.fastpath_events
+    /// The keystrokes which type `text`, one group of events per character. Characters
+    /// which the layout can't produce are sent as Unicode even in scan code mode, and
+    /// line feeds and tabs are sent as the Enter and Tab keys.
+    pub fn text_keystrokes(&self, text: &str) -> Vec<Vec<FastPathInputEvent>> {
+        let key =
+            |key: PhysicalKey| [key.scancode().events(true), key.scancode().events(false)].concat();
+        text.chars()
+            .filter(|c| *c != '\r')
+            .map(|c| match c {
+                '\n' => key(PhysicalKey::Enter),
+                '\t' => key(PhysicalKey::Tab),
+                c => self
+                    .type_char(c)
+                    .filter(|_| self.mode == RDPKeyboardMode::Scancode)
+                    .unwrap_or_else(|| unicode_events(c.encode_utf8(&mut [0; 4]))),
+            })
+            .collect()
+    }
+
+    /// Release every key and modifier the server thinks is down; keys first, then
+    /// modifiers in the reverse of the order they were pressed.
+    pub fn release_all(&mut self) -> Vec<FastPathInputEvent> {
+        let keys = std::mem::take(&mut self.keys_down);
+        let modifiers = std::mem::take(&mut self.modifiers_down);
+        keys.into_iter()
+            .rev()
+            .chain(modifiers.into_iter().rev().map(RDPModifier::physical_key))
+            .flat_map(|key| key.scancode().events(false))
+            .collect()
     }
 }
 
-pub static SCANCODE_MODIFIER_ALT: u8 = 0x38;
-pub static SCANCODE_MODIFIER_CTRL: u8 = 0x1d;
-pub static SCANCODE_MODIFIER_SHIFT: u8 = 0x2a;
+/// Send keystrokes in order with a pause after each, since some applications drop
+/// input which arrives faster than a person could type.
+pub async fn send_keystrokes(
+    tx: &tokio::sync::mpsc::Sender<Vec<FastPathInputEvent>>,
+    keystrokes: Vec<Vec<FastPathInputEvent>>,
+    delay: std::time::Duration,
+) -> anyhow::Result<()> {
+    if delay.is_zero() {
+        tx.send(keystrokes.concat()).await?;
+        return Ok(());
+    }
+    for keystroke in keystrokes {
+        tx.send(keystroke).await?;
+        tokio::time::sleep(delay).await;
+    }
+    Ok(())
+}
+
+/// Press and release events for each UTF-16 code unit of `text`; surrogate pairs
+/// are sent as two consecutive units, as Windows expects.
+pub fn unicode_events(text: &str) -> Vec<FastPathInputEvent> {
+    text.encode_utf16()
+        .flat_map(|unit| {
+            [
+                FastPathInputEvent::UnicodeKeyboardEvent(KeyboardFlags::empty(), unit),
+                FastPathInputEvent::UnicodeKeyboardEvent(KeyboardFlags::RELEASE, unit),
+            ]
+        })
+        .collect()
+}
+
+/// Whether a key normally produces a character, rather than being a control or
+/// navigation key.
+fn is_text_key(key: &Key) -> bool {
+    matches!(
+        key,
+        Key::Space
+            | Key::Colon
+            | Key::Comma
+            | Key::Backslash
+            | Key::Slash
+            | Key::Pipe
+            | Key::Questionmark
+            | Key::Exclamationmark
+            | Key::OpenBracket
+            | Key::CloseBracket
+            | Key::OpenCurlyBracket
+            | Key::CloseCurlyBracket
+            | Key::Backtick
+            | Key::Minus
+            | Key::Period
+            | Key::Plus
+            | Key::Equals
+            | Key::Semicolon
+            | Key::Quote
+            | Key::A
+            | Key::B
+            | Key::C
+            | Key::D
+            | Key::E
+            | Key::F
+            | Key::G
+            | Key::H
+            | Key::I
+            | Key::J
+            | Key::K
+            | Key::L
+            | Key::M
+            | Key::N
+            | Key::O
+            | Key::P
+            | Key::Q
+            | Key::R
+            | Key::S
+            | Key::T
+            | Key::U
+            | Key::V
+            | Key::W
+            | Key::X
+            | Key::Y
+            | Key::Z
+            | Key::Num0
+            | Key::Num1
+            | Key::Num2
+            | Key::Num3
+            | Key::Num4
+            | Key::Num5
+            | Key::Num6
+            | Key::Num7
+            | Key::Num8
+            | Key::Num9
+    )
+}
 
-/// Convert special keys to scan codes as given on
-/// https://learn.microsoft.com/en-us/windows/win32/inputdev/about-keyboard-input
-/// Returns a bool to indicate whether the scan code requires the
-/// extended scancode prefix (0xE0)
-pub fn get_scancode(key: &Key) -> anyhow::Result<(bool, u8)> {
+/// Find the physical key behind an egui key. egui folds the numeric keypad into
+/// the main block and has no codes for lock, media and similar keys, so only the
+/// keys it can report are covered here; the README lists what can't be forwarded.
+pub fn physical_key_from_egui(key: &Key) -> anyhow::Result<PhysicalKey> {
     Ok(match key {
-        Key::Space => (false, 0x39),
-        Key::Delete => (false, 0xd3),
-        Key::Backspace => (false, 0x0e),
-        Key::Insert => (true, 0x52),
-        Key::Tab => (false, 0x0f),
-        Key::Enter => (false, 0x1c), // Same as enter for Win32?
-        Key::Escape => (false, 0x01),
-        Key::Home => (true, 0x47),
-        Key::ArrowLeft => (true, 0x4b),  /* Move left, left arrow */
-        Key::ArrowUp => (true, 0x48),    /* Move up, up arrow */
-        Key::ArrowRight => (true, 0x4d), /* Move right, right arrow */
-        Key::ArrowDown => (true, 0x50),  /* Move down, down arrow */
-        Key::PageUp => (true, 0x49),
-        Key::PageDown => (true, 0x51),
-        Key::End => (true, 0x4f), /* EOL */
-        Key::F1 => (false, 0x3b),
-        Key::F2 => (false, 0x3c),
-        Key::F3 => (false, 0x3d),
-        Key::F4 => (false, 0x3e),
-        Key::F5 => (false, 0x3f),
-        Key::F6 => (false, 0x40),
-        Key::F7 => (false, 0x41),
-        Key::F8 => (false, 0x42),
-        Key::F9 => (false, 0x43),
-        Key::F10 => (false, 0x44),
-        Key::F11 => (false, 0x57),
-        Key::F12 => (false, 0x58),
-        Key::F13 => (false, 0x64),
-        Key::F14 => (false, 0x65),
-        Key::F15 => (false, 0x66),
-        Key::F16 => (false, 0x67),
-        Key::F17 => (false, 0x68),
-        Key::F18 => (false, 0x69),
-        Key::F19 => (false, 0x6a),
-        Key::F20 => (false, 0x6b),
-        Key::F21 => (false, 0x6c),
-        Key::F22 => (false, 0x6d),
-        Key::F23 => (false, 0x6e),
-        Key::F24 => (false, 0x76),
-        Key::Colon => (false, 0x27),
-        Key::Comma => (false, 0x33),
-        Key::Backslash => (false, 0x2b), // Named backwards in egui?
-        Key::Slash | Key::Pipe => (false, 0x35),
-        Key::Backtick => (false, 0x29),
-        Key::OpenBracket => (false, 0x1a),
-        Key::CloseBracket => (false, 0x1b),
-        Key::OpenCurlyBracket => (false, 0x1a),
-        Key::CloseCurlyBracket => (false, 0x1b),
-        Key::Equals => (false, 0x0d),
-        Key::Exclamationmark => (false, 0x02),
-        // Not sure what these would be mapped to in a Windows environment
-        Key::Copy
-        | Key::Cut
-        | Key::Paste
-        | Key::F25
+        Key::ArrowDown => PhysicalKey::ArrowDown,
+        Key::ArrowLeft => PhysicalKey::ArrowLeft,
+        Key::ArrowRight => PhysicalKey::ArrowRight,
+        Key::ArrowUp => PhysicalKey::ArrowUp,
+        Key::Escape => PhysicalKey::Escape,
+        Key::Tab => PhysicalKey::Tab,
+        Key::Backspace => PhysicalKey::Backspace,
+        Key::Enter => PhysicalKey::Enter,
+        Key::Space => PhysicalKey::Space,
+        Key::Insert => PhysicalKey::Insert,
+        Key::Delete => PhysicalKey::Delete,
+        Key::Home => PhysicalKey::Home,
+        Key::End => PhysicalKey::End,
+        Key::PageUp => PhysicalKey::PageUp,
+        Key::PageDown => PhysicalKey::PageDown,
+        Key::Copy => PhysicalKey::Copy,
+        Key::Cut => PhysicalKey::Cut,
+        Key::Paste => PhysicalKey::Paste,
+        // The remaining punctuation is named for the character, which may be
+        // shifted; map it to the key it is on in the US layout.
+        Key::Colon | Key::Semicolon => PhysicalKey::Semicolon,
+        Key::Comma => PhysicalKey::Comma,
+        Key::Backslash | Key::Pipe => PhysicalKey::Backslash, // Named backwards in egui?
+        Key::Slash | Key::Questionmark => PhysicalKey::Slash,
+        Key::Exclamationmark => PhysicalKey::Digit1,
+        Key::OpenBracket | Key::OpenCurlyBracket => PhysicalKey::BracketLeft,
+        Key::CloseBracket | Key::CloseCurlyBracket => PhysicalKey::BracketRight,
+        Key::Backtick => PhysicalKey::Backquote,
+        Key::Minus => PhysicalKey::Minus,
+        Key::Period => PhysicalKey::Period,
+        Key::Plus => PhysicalKey::NumpadAdd, // egui only reports the keypad key as Plus
+        Key::Equals => PhysicalKey::Equal,
+        Key::Quote => PhysicalKey::Quote,
+        Key::Num0 => PhysicalKey::Digit0,
+        Key::Num1 => PhysicalKey::Digit1,
+        Key::Num2 => PhysicalKey::Digit2,
+        Key::Num3 => PhysicalKey::Digit3,
+        Key::Num4 => PhysicalKey::Digit4,
+        Key::Num5 => PhysicalKey::Digit5,
+        Key::Num6 => PhysicalKey::Digit6,
+        Key::Num7 => PhysicalKey::Digit7,
+        Key::Num8 => PhysicalKey::Digit8,
+        Key::Num9 => PhysicalKey::Digit9,
+        Key::A => PhysicalKey::KeyA,
+        Key::B => PhysicalKey::KeyB,
+        Key::C => PhysicalKey::KeyC,
+        Key::D => PhysicalKey::KeyD,
+        Key::E => PhysicalKey::KeyE,
+        Key::F => PhysicalKey::KeyF,
+        Key::G => PhysicalKey::KeyG,
+        Key::H => PhysicalKey::KeyH,
+        Key::I => PhysicalKey::KeyI,
+        Key::J => PhysicalKey::KeyJ,
+        Key::K => PhysicalKey::KeyK,
+        Key::L => PhysicalKey::KeyL,
+        Key::M => PhysicalKey::KeyM,
+        Key::N => PhysicalKey::KeyN,
+        Key::O => PhysicalKey::KeyO,
+        Key::P => PhysicalKey::KeyP,
+        Key::Q => PhysicalKey::KeyQ,
+        Key::R => PhysicalKey::KeyR,
+        Key::S => PhysicalKey::KeyS,
+        Key::T => PhysicalKey::KeyT,
+        Key::U => PhysicalKey::KeyU,
+        Key::V => PhysicalKey::KeyV,
+        Key::W => PhysicalKey::KeyW,
+        Key::X => PhysicalKey::KeyX,
+        Key::Y => PhysicalKey::KeyY,
+        Key::Z => PhysicalKey::KeyZ,
+        Key::F1 => PhysicalKey::F1,
+        Key::F2 => PhysicalKey::F2,
+        Key::F3 => PhysicalKey::F3,
+        Key::F4 => PhysicalKey::F4,
+        Key::F5 => PhysicalKey::F5,
+        Key::F6 => PhysicalKey::F6,
+        Key::F7 => PhysicalKey::F7,
+        Key::F8 => PhysicalKey::F8,
+        Key::F9 => PhysicalKey::F9,
+        Key::F10 => PhysicalKey::F10,
+        Key::F11 => PhysicalKey::F11,
+        Key::F12 => PhysicalKey::F12,
+        Key::F13 => PhysicalKey::F13,
+        Key::F14 => PhysicalKey::F14,
+        Key::F15 => PhysicalKey::F15,
+        Key::F16 => PhysicalKey::F16,
+        Key::F17 => PhysicalKey::F17,
+        Key::F18 => PhysicalKey::F18,
+        Key::F19 => PhysicalKey::F19,
+        Key::F20 => PhysicalKey::F20,
+        Key::F21 => PhysicalKey::F21,
+        Key::F22 => PhysicalKey::F22,
+        Key::F23 => PhysicalKey::F23,
+        Key::F24 => PhysicalKey::F24,
+        // There are no Set-1 scan codes beyond F24.
+        Key::F25
         | Key::F26
         | Key::F27
         | Key::F28
@@ -148,48 +564,278 @@ pub fn get_scancode(key: &Key) -> anyhow::Result<(bool, u8)> {
         | Key::F32
         | Key::F33
         | Key::F34
-        | Key::F35 => return Err(anyhow::anyhow!("Key {:?} is not mapped", key)),
-        Key::Period => (false, 0x34),
-        Key::Minus => (false, 0x0c),
-        Key::Plus => (false, 0x4e), // Keypad
-        Key::Quote => (false, 0x28),
-        Key::Questionmark => (false, 0x35),
-        Key::Semicolon => (false, 0x27),
-        Key::A => (false, 0x1e),
-        Key::B => (false, 0x30),
-        Key::C => (false, 0x2e),
-        Key::D => (false, 0x20),
-        Key::E => (false, 0x12),
-        Key::F => (false, 0x21),
-        Key::G => (false, 0x22),
-        Key::H => (false, 0x23),
-        Key::I => (false, 0x17),
-        Key::J => (false, 0x24),
-        Key::K => (false, 0x25),
-        Key::L => (false, 0x26),
-        Key::M => (false, 0x32),
-        Key::N => (false, 0x31),
-        Key::O => (false, 0x18),
-        Key::P => (false, 0x19),
-        Key::Q => (false, 0x10),
-        Key::R => (false, 0x13),
-        Key::S => (false, 0x1f),
-        Key::T => (false, 0x14),
-        Key::U => (false, 0x16),
-        Key::V => (false, 0x2f),
-        Key::W => (false, 0x11),
-        Key::X => (false, 0x2d),
-        Key::Y => (false, 0x15),
-        Key::Z => (false, 0x2c),
-        Key::Num0 => (false, 0x0b),
-        Key::Num1 => (false, 0x02),
-        Key::Num2 => (false, 0x03),
-        Key::Num3 => (false, 0x04),
-        Key::Num4 => (false, 0x05),
-        Key::Num5 => (false, 0x06),
-        Key::Num6 => (false, 0x07),
-        Key::Num7 => (false, 0x08),
-        Key::Num8 => (false, 0x09),
-        Key::Num9 => (false, 0x0a),
+        | Key::F35 => return Err(anyhow::anyhow!("Key {:?} has no scan code", key)),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn egui_keys() {
+        let cases = [
+            (Key::A, PhysicalKey::KeyA),
+            (Key::Num0, PhysicalKey::Digit0),
+            (Key::Exclamationmark, PhysicalKey::Digit1),
+            (Key::Pipe, PhysicalKey::Backslash),
+            (Key::Questionmark, PhysicalKey::Slash),
+            (Key::Plus, PhysicalKey::NumpadAdd),
+            (Key::Delete, PhysicalKey::Delete),
+            (Key::Copy, PhysicalKey::Copy),
+            (Key::F24, PhysicalKey::F24),
+        ];
+        for (key, expected) in cases {
+            assert_eq!(physical_key_from_egui(&key).unwrap(), expected, "{:?}", key);
+        }
+        assert!(physical_key_from_egui(&Key::F25).is_err());
+    }
+
+    #[test]
+    fn text_without_key_typed_through_layout() {
+        let de = "de-DE".parse().unwrap();
+        let mut state = RDPKeyboardState::new(RDPKeyboardMode::Scancode, Some(de));
+        let press_a = Event::Key {
+            key: Key::A,
+            physical_key: Some(Key::A),
+            pressed: true,
+            repeat: false,
+            modifiers: Modifiers::NONE,
+        };
+
+        assert!(state.maybe_from(&press_a).is_some());
+        assert!(state.maybe_from(&Event::Text("a".to_string())).is_none());
+        let events = state
+            .maybe_from(&Event::Text(">".to_string()))
+            .unwrap()
+            .into_fastpath_events();
+        assert_eq!(
+            events,
+            [
+                PhysicalKey::ShiftLeft.scancode().events(true),
+                PhysicalKey::IntlBackslash.scancode().events(true),
+                PhysicalKey::IntlBackslash.scancode().events(false),
+                PhysicalKey::ShiftLeft.scancode().events(false),
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn text_keystrokes() {
+        let press = |key: PhysicalKey| key.scancode().events(true);
+        let release = |key: PhysicalKey| key.scancode().events(false);
+        let us = "en-US".parse().unwrap();
+
+        let state = RDPKeyboardState::new(RDPKeyboardMode::Scancode, Some(us));
+        assert_eq!(
+            state.text_keystrokes("aB\n\u{e9}"),
+            vec![
+                [press(PhysicalKey::KeyA), release(PhysicalKey::KeyA)].concat(),
+                [
+                    press(PhysicalKey::ShiftLeft),
+                    press(PhysicalKey::KeyB),
+                    release(PhysicalKey::KeyB),
+                    release(PhysicalKey::ShiftLeft),
+                ]
+                .concat(),
+                [press(PhysicalKey::Enter), release(PhysicalKey::Enter)].concat(),
+                unicode_events("\u{e9}"),
+            ]
+        );
+
+        let state = RDPKeyboardState::new(RDPKeyboardMode::Unicode, Some(us));
+        assert_eq!(state.text_keystrokes("a"), vec![unicode_events("a")]);
+    }
+
+    #[test]
+    fn lock_keys_only_synced_on_change() {
+        let mut state = RDPKeyboardState::default();
+        let caps = RDPLockKeys {
+            caps_lock: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            state.sync_lock_keys(caps, false),
+            Some(vec![FastPathInputEvent::SyncEvent(
+                SynchronizeFlags::CAPS_LOCK
+            )])
+        );
+        assert_eq!(state.sync_lock_keys(caps, false), None);
+        assert!(state.sync_lock_keys(caps, true).is_some());
+        assert_eq!(
+            state.sync_lock_keys(RDPLockKeys::default(), false),
+            Some(vec![FastPathInputEvent::SyncEvent(
+                SynchronizeFlags::empty()
+            )])
+        );
+    }
+
+    #[test]
+    fn clipboard_shortcut_sent_on_release() {
+        let mut state = RDPKeyboardState::default();
+        let ctrl = Modifiers::CTRL;
+        let release_c = Event::Key {
+            key: Key::C,
+            physical_key: Some(Key::C),
+            pressed: false,
+            repeat: false,
+            modifiers: ctrl,
+        };
+
+        assert!(state.maybe_from(&Event::Copy).is_none());
+        let events = state.maybe_from(&release_c).unwrap().into_fastpath_events();
+        assert_eq!(
+            events,
+            [
+                PhysicalKey::ControlLeft.scancode().events(true),
+                PhysicalKey::KeyC.scancode().events(true),
+                PhysicalKey::KeyC.scancode().events(false),
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn shortcut_released_after_modifier_in_unicode_mode() {
+        let mut state = RDPKeyboardState::new(RDPKeyboardMode::Unicode, None);
+        let key_c = |pressed, modifiers| Event::Key {
+            key: Key::C,
+            physical_key: Some(Key::C),
+            pressed,
+            repeat: false,
+            modifiers,
+        };
+
+        // egui has no events for modifiers, so Ctrl going up shows on the C release.
+        assert_eq!(
+            state
+                .maybe_from(&key_c(true, Modifiers::CTRL))
+                .unwrap()
+                .into_fastpath_events(),
+            [
+                PhysicalKey::ControlLeft.scancode().events(true),
+                PhysicalKey::KeyC.scancode().events(true),
+            ]
+            .concat()
+        );
+        assert_eq!(
+            state
+                .maybe_from(&key_c(false, Modifiers::NONE))
+                .unwrap()
+                .into_fastpath_events(),
+            [
+                PhysicalKey::ControlLeft.scancode().events(false),
+                PhysicalKey::KeyC.scancode().events(false),
+            ]
+            .concat()
+        );
+        assert_eq!(state.release_all(), []);
+    }
+
+    #[test]
+    fn modifiers_synced_by_delta() {
+        let press = |key: PhysicalKey| key.scancode().events(true);
+        let release = |key: PhysicalKey| key.scancode().events(false);
+        let mut state = RDPKeyboardState::default();
+
+        assert_eq!(
+            state.sync_modifiers(&(Modifiers::CTRL | Modifiers::SHIFT)),
+            [
+                press(PhysicalKey::ControlLeft),
+                press(PhysicalKey::ShiftLeft)
+            ]
+            .concat()
+        );
+        assert_eq!(
+            state.sync_modifiers(&(Modifiers::CTRL | Modifiers::SHIFT)),
+            []
+        );
+        assert_eq!(
+            state.sync_modifiers(&Modifiers::SHIFT),
+            release(PhysicalKey::ControlLeft)
+        );
+
+        // Where the local hands are known, those are the ones pressed.
+        state.set_local_modifiers(Some(vec![RDPModifier::RightShift, RDPModifier::LeftMeta]));
+        assert_eq!(
+            state.sync_modifiers(&Modifiers::SHIFT),
+            [
+                release(PhysicalKey::ShiftLeft),
+                press(PhysicalKey::ShiftRight),
+                press(PhysicalKey::MetaLeft),
+            ]
+            .concat()
+        );
+        assert_eq!(state.sync_modifiers(&Modifiers::SHIFT), []);
+        state.set_local_modifiers(Some(Vec::new()));
+        assert_eq!(
+            state.sync_modifiers(&Modifiers::NONE),
+            [
+                release(PhysicalKey::ShiftRight),
+                release(PhysicalKey::MetaLeft)
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn release_all_lifts_keys_then_modifiers() {
+        let mut state = RDPKeyboardState::default();
+        let key = |key, pressed| Event::Key {
+            key,
+            physical_key: Some(key),
+            pressed,
+            repeat: false,
+            modifiers: Modifiers::CTRL | Modifiers::ALT,
+        };
+        state.maybe_from(&key(Key::A, true));
+        state.maybe_from(&key(Key::B, true));
+
+        assert_eq!(
+            state.release_all(),
+            [
+                PhysicalKey::KeyB.scancode().events(false),
+                PhysicalKey::KeyA.scancode().events(false),
+                PhysicalKey::AltLeft.scancode().events(false),
+                PhysicalKey::ControlLeft.scancode().events(false),
+            ]
+            .concat()
+        );
+        assert_eq!(state.release_all(), []);
+    }
+
+    #[test]
+    fn text_expected_only_until_next_key_event() {
+        let us = "en-US".parse().unwrap();
+        let mut state = RDPKeyboardState::new(RDPKeyboardMode::Scancode, Some(us));
+        let key = |key, pressed| Event::Key {
+            key,
+            physical_key: Some(key),
+            pressed,
+            repeat: false,
+            modifiers: Modifiers::NONE,
+        };
+        let typed_a = [
+            PhysicalKey::KeyA.scancode().events(true),
+            PhysicalKey::KeyA.scancode().events(false),
+        ]
+        .concat();
+
+        // A press whose text never turns up doesn't swallow text typed later.
+        state.maybe_from(&key(Key::A, true));
+        state.maybe_from(&key(Key::A, false));
+        assert_eq!(
+            state
+                .maybe_from(&Event::Text("a".to_string()))
+                .unwrap()
+                .into_fastpath_events(),
+            typed_a
+        );
+
+        state.maybe_from(&key(Key::A, true));
+        state.maybe_from(&key(Key::ArrowLeft, true));
+        assert!(state.maybe_from(&Event::Text("a".to_string())).is_some());
+    }
+}
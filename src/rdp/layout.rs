@@ -0,0 +1,390 @@
+use std::str::FromStr;
+
+use super::scancode::PhysicalKey;
+
+/// Which modifiers must be held to produce a character from a key.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyLevel {
+    Normal,
+    Shift,
+    AltGr,
+}
+
+/// The keys of the main alphanumeric block, in the order used by the character
+/// strings in [`LayoutChars`].
+static ALPHANUMERIC_KEYS: [PhysicalKey; 48] = [
+    PhysicalKey::Backquote,
+    PhysicalKey::Digit1,
+    PhysicalKey::Digit2,
+    PhysicalKey::Digit3,
+    PhysicalKey::Digit4,
+    PhysicalKey::Digit5,
+    PhysicalKey::Digit6,
+    PhysicalKey::Digit7,
+    PhysicalKey::Digit8,
+    PhysicalKey::Digit9,
+    PhysicalKey::Digit0,
+    PhysicalKey::Minus,
+    PhysicalKey::Equal,
+    PhysicalKey::KeyQ,
+    PhysicalKey::KeyW,
+    PhysicalKey::KeyE,
+    PhysicalKey::KeyR,
+    PhysicalKey::KeyT,
+    PhysicalKey::KeyY,
+    PhysicalKey::KeyU,
+    PhysicalKey::KeyI,
+    PhysicalKey::KeyO,
+    PhysicalKey::KeyP,
+    PhysicalKey::BracketLeft,
+    PhysicalKey::BracketRight,
+    PhysicalKey::KeyA,
+    PhysicalKey::KeyS,
+    PhysicalKey::KeyD,
+    PhysicalKey::KeyF,
+    PhysicalKey::KeyG,
+    PhysicalKey::KeyH,
+    PhysicalKey::KeyJ,
+    PhysicalKey::KeyK,
+    PhysicalKey::KeyL,
+    PhysicalKey::Semicolon,
+    PhysicalKey::Quote,
+    PhysicalKey::Backslash,
+    PhysicalKey::IntlBackslash,
+    PhysicalKey::KeyZ,
+    PhysicalKey::KeyX,
+    PhysicalKey::KeyC,
+    PhysicalKey::KeyV,
+    PhysicalKey::KeyB,
+    PhysicalKey::KeyN,
+    PhysicalKey::KeyM,
+    PhysicalKey::Comma,
+    PhysicalKey::Period,
+    PhysicalKey::Slash,
+];
+
+/// Characters produced by each key of [`ALPHANUMERIC_KEYS`], one per key. A space
+/// marks a key that produces nothing at that level, or only a dead key, which we
+/// don't try to compose with.
+pub struct LayoutChars {
+    normal: &'static str,
+    shift: &'static str,
+    altgr: &'static [(PhysicalKey, char)],
+}
+
+static US: LayoutChars = LayoutChars {
+    normal: "`1234567890-=qwertyuiop[]asdfghjkl;'\\ zxcvbnm,./",
+    shift: "~!@#$%^&*()_+QWERTYUIOP{}ASDFGHJKL:\"| ZXCVBNM<>?",
+    altgr: &[],
+};
+
+static UK: LayoutChars = LayoutChars {
+    normal: "`1234567890-=qwertyuiop[]asdfghjkl;'#\\zxcvbnm,./",
+    shift: "¬!\"£$%^&*()_+QWERTYUIOP{}ASDFGHJKL:@~|ZXCVBNM<>?",
+    altgr: &[(PhysicalKey::Backquote, '¦'), (PhysicalKey::Digit4, '€')],
+};
+
+static GERMAN: LayoutChars = LayoutChars {
+    normal: " 1234567890ß qwertzuiopü+asdfghjklöä#<yxcvbnm,.-",
+    shift: "°!\"§$%&/()=? QWERTZUIOPÜ*ASDFGHJKLÖÄ'>YXCVBNM;:_",
+    altgr: &[
+        (PhysicalKey::Digit2, '²'),
+        (PhysicalKey::Digit3, '³'),
+        (PhysicalKey::Digit7, '{'),
+        (PhysicalKey::Digit8, '['),
+        (PhysicalKey::Digit9, ']'),
+        (PhysicalKey::Digit0, '}'),
+        (PhysicalKey::Minus, '\\'),
+        (PhysicalKey::KeyQ, '@'),
+        (PhysicalKey::KeyE, '€'),
+        (PhysicalKey::BracketRight, '~'),
+        (PhysicalKey::IntlBackslash, '|'),
+        (PhysicalKey::KeyM, 'µ'),
+    ],
+};
+
+static FRENCH: LayoutChars = LayoutChars {
+    normal: "²&é\"'(-è_çà)=azertyuiop $qsdfghjklmù*<wxcvbn,;:!",
+    shift: " 1234567890°+AZERTYUIOP £QSDFGHJKLM%µ>WXCVBN?./§",
+    altgr: &[
+        (PhysicalKey::Digit3, '#'),
+        (PhysicalKey::Digit4, '{'),
+        (PhysicalKey::Digit5, '['),
+        (PhysicalKey::Digit6, '|'),
+        (PhysicalKey::Digit8, '\\'),
+        (PhysicalKey::Digit9, '^'),
+        (PhysicalKey::Digit0, '@'),
+        (PhysicalKey::Minus, ']'),
+        (PhysicalKey::Equal, '}'),
+        (PhysicalKey::KeyE, '€'),
+    ],
+};
+
+/// A Windows keyboard layout, identified to the server by its KLID.
+#[derive(Clone, Copy)]
+pub struct KeyboardLayout {
+    pub name: &'static str,
+    pub klid: u32,
+    /// XKB layout (and optional variant) that corresponds to this one.
+    xkb: &'static str,
+    chars: Option<&'static LayoutChars>,
+}
+
+const fn layout(
+    name: &'static str,
+    klid: u32,
+    xkb: &'static str,
+    chars: Option<&'static LayoutChars>,
+) -> KeyboardLayout {
+    KeyboardLayout {
+        name,
+        klid,
+        xkb,
+        chars,
+    }
+}
+
+/// Layouts we know by name. Only a few have character tables; the rest can be
+/// negotiated but not typed into in scan code mode except by their physical keys.
+pub static KEYBOARD_LAYOUTS: &[KeyboardLayout] = &[
+    layout("en-US", 0x0000_0409, "us", Some(&US)),
+    layout("en-US-dvorak", 0x0001_0409, "us(dvorak)", None),
+    layout("en-GB", 0x0000_0809, "gb", Some(&UK)),
+    layout("de-DE", 0x0000_0407, "de", Some(&GERMAN)),
+    layout("de-CH", 0x0000_0807, "ch", None),
+    layout("fr-FR", 0x0000_040c, "fr", Some(&FRENCH)),
+    layout("fr-BE", 0x0000_080c, "be", None),
+    layout("fr-CH", 0x0000_100c, "ch(fr)", None),
+    layout("es-ES", 0x0000_040a, "es", None),
+    layout("it-IT", 0x0000_0410, "it", None),
+    layout("pt-PT", 0x0000_0816, "pt", None),
+    layout("pt-BR", 0x0000_0416, "br", None),
+    layout("nl-NL", 0x0000_0413, "nl", None),
+    layout("da-DK", 0x0000_0406, "dk", None),
+    layout("sv-SE", 0x0000_041d, "se", None),
+    layout("nb-NO", 0x0000_0414, "no", None),
+    layout("fi-FI", 0x0000_040b, "fi", None),
+    layout("pl-PL", 0x0000_0415, "pl", None),
+    layout("cs-CZ", 0x0000_0405, "cz", None),
+    layout("hu-HU", 0x0000_040e, "hu", None),
+    layout("tr-TR", 0x0000_041f, "tr", None),
+    layout("ru-RU", 0x0000_0419, "ru", None),
+    layout("ja-JP", 0x0000_0411, "jp", None),
+    layout("ko-KR", 0x0000_0412, "kr", None),
+    layout("zh-CN", 0x0000_0804, "cn", None),
+];
+
+impl KeyboardLayout {
+    pub fn from_klid(klid: u32) -> KeyboardLayout {
+        KEYBOARD_LAYOUTS
+            .iter()
+            .find(|l| l.klid == klid)
+            .copied()
+            .unwrap_or(layout("custom", klid, "", None))
+    }
+
+    /// The physical key and modifiers which type `c` in this layout, if known.
+    pub fn keystroke(&self, c: char) -> Option<(PhysicalKey, KeyLevel)> {
+        if c == ' ' {
+            return Some((PhysicalKey::Space, KeyLevel::Normal));
+        }
+        let chars = self.chars?;
+        let find = |level: &str| {
+            level
+                .chars()
+                .zip(ALPHANUMERIC_KEYS)
+                .find(|(ch, _)| *ch == c)
+                .map(|(_, key)| key)
+        };
+        find(chars.normal)
+            .map(|key| (key, KeyLevel::Normal))
+            .or_else(|| find(chars.shift).map(|key| (key, KeyLevel::Shift)))
+            .or_else(|| {
+                chars
+                    .altgr
+                    .iter()
+                    .find(|(_, ch)| *ch == c)
+                    .map(|(key, _)| (*key, KeyLevel::AltGr))
+            })
+    }
+
+    /// Guess the local layout from the XKB configuration or, failing that, the locale.
+    pub fn detect() -> Option<KeyboardLayout> {
+        let by_name = |name: &str| {
+            KEYBOARD_LAYOUTS
+                .iter()
+                .find(|l| l.name.eq_ignore_ascii_case(&name.replace('_', "-")))
+                .copied()
+        };
+
+        if let Some((layout, variant)) = xkb_layout() {
+            // Fall back to the plain layout for variants we don't know, e.g. de(nodeadkeys).
+            let with_variant = variant.map(|variant| format!("{}({})", layout, variant));
+            let found = [with_variant, Some(layout)]
+                .into_iter()
+                .flatten()
+                .find_map(|xkb| KEYBOARD_LAYOUTS.iter().find(|l| l.xkb == xkb).copied());
+            if found.is_some() {
+                return found;
+            }
+        }
+
+        ["LC_ALL", "LC_CTYPE", "LANG"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|locale| !locale.is_empty())
+            .and_then(|locale| {
+                // e.g. de_DE.UTF-8@euro
+                by_name(locale.split(['.', '@']).next().unwrap_or_default())
+            })
+    }
+}
+
+impl std::fmt::Debug for KeyboardLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (0x{:08x})", self.name, self.klid)
+    }
+}
+
+impl PartialEq for KeyboardLayout {
+    fn eq(&self, other: &Self) -> bool {
+        self.klid == other.klid
+    }
+}
+
+impl FromStr for KeyboardLayout {
+    type Err = anyhow::Error;
+
+    /// Accepts a name such as `de-DE` (or `de_DE`) or a KLID such as `0x00000407`
+    /// or `00000407`, as found in the Windows registry.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.replace('_', "-");
+        if let Some(layout) = KEYBOARD_LAYOUTS
+            .iter()
+            .find(|l| l.name.eq_ignore_ascii_case(&name))
+        {
+            return Ok(*layout);
+        }
+
+        let hex = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => hex,
+            None if s.len() == 8 => s,
+            None => return Err(anyhow::anyhow!("Unknown keyboard layout '{}'", s)),
+        };
+        u32::from_str_radix(hex, 16)
+            .map(KeyboardLayout::from_klid)
+            .map_err(|_| anyhow::anyhow!("Unknown keyboard layout '{}'", s))
+    }
+}
+
+/// The first configured XKB layout and its variant, from the environment (as used
+/// by Wayland compositors), the X server, or the Debian-style system default.
+fn xkb_layout() -> Option<(String, Option<String>)> {
+    let first = |s: &str| s.split(',').next().unwrap_or_default().trim().to_string();
+
+    if let Ok(layout) = std::env::var("XKB_DEFAULT_LAYOUT") {
+        let variant = std::env::var("XKB_DEFAULT_VARIANT").ok();
+        let variant = variant.map(|v| first(&v)).filter(|v| !v.is_empty());
+        return Some((first(&layout), variant));
+    }
+
+    let query = std::process::Command::new("setxkbmap")
+        .arg("-query")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned());
+    if let Some(layout) = query.and_then(|q| parse_xkb_settings(&q, ':')) {
+        return Some(layout);
+    }
+
+    std::fs::read_to_string("/etc/default/keyboard")
+        .ok()
+        .and_then(|f| parse_xkb_settings(&f.replace("XKB", ""), '='))
+}
+
+/// Pick the layout and variant out of `setxkbmap -query` or /etc/default/keyboard
+/// style output.
+fn parse_xkb_settings(settings: &str, separator: char) -> Option<(String, Option<String>)> {
+    let value = |key: &str| {
+        settings.lines().find_map(|line| {
+            let (k, v) = line.split_once(separator)?;
+            k.trim().eq_ignore_ascii_case(key).then(|| {
+                let v = v.trim().trim_matches('"');
+                v.split(',').next().unwrap_or_default().to_string()
+            })
+        })
+    };
+    let layout = value("layout").filter(|l| !l.is_empty())?;
+    Some((layout, value("variant").filter(|v| !v.is_empty())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_layouts() {
+        let cases = [
+            ("de-DE", 0x0000_0407),
+            ("de_de", 0x0000_0407),
+            ("0x00000407", 0x0000_0407),
+            ("00000809", 0x0000_0809),
+            ("0x00010409", 0x0001_0409),
+            ("0x00000999", 0x0000_0999),
+        ];
+        for (s, klid) in cases {
+            assert_eq!(s.parse::<KeyboardLayout>().unwrap().klid, klid, "{}", s);
+        }
+        assert!("xx-XX".parse::<KeyboardLayout>().is_err());
+        assert!("C".parse::<KeyboardLayout>().is_err());
+    }
+
+    #[test]
+    fn character_tables_cover_every_key() {
+        for layout in KEYBOARD_LAYOUTS {
+            if let Some(chars) = layout.chars {
+                assert_eq!(chars.normal.chars().count(), 48, "{}", layout.name);
+                assert_eq!(chars.shift.chars().count(), 48, "{}", layout.name);
+            }
+        }
+    }
+
+    #[test]
+    fn keystrokes() {
+        let us: KeyboardLayout = "en-US".parse().unwrap();
+        let de: KeyboardLayout = "de-DE".parse().unwrap();
+        let fr: KeyboardLayout = "fr-FR".parse().unwrap();
+        let cases = [
+            (us, 'a', Some((PhysicalKey::KeyA, KeyLevel::Normal))),
+            (us, 'A', Some((PhysicalKey::KeyA, KeyLevel::Shift))),
+            (us, '|', Some((PhysicalKey::Backslash, KeyLevel::Shift))),
+            (de, 'z', Some((PhysicalKey::KeyY, KeyLevel::Normal))),
+            (de, 'ö', Some((PhysicalKey::Semicolon, KeyLevel::Normal))),
+            (de, '@', Some((PhysicalKey::KeyQ, KeyLevel::AltGr))),
+            (de, '>', Some((PhysicalKey::IntlBackslash, KeyLevel::Shift))),
+            (fr, 'a', Some((PhysicalKey::KeyQ, KeyLevel::Normal))),
+            (fr, '1', Some((PhysicalKey::Digit1, KeyLevel::Shift))),
+            (fr, ' ', Some((PhysicalKey::Space, KeyLevel::Normal))),
+            (us, 'é', None),
+        ];
+        for (layout, c, expected) in cases {
+            assert_eq!(layout.keystroke(c), expected, "{:?} {:?}", layout, c);
+        }
+    }
+
+    #[test]
+    fn xkb_settings() {
+        let query =
+            "rules:      evdev\nmodel:      pc105\nlayout:     de,us\nvariant:    nodeadkeys,\n";
+        assert_eq!(
+            parse_xkb_settings(query, ':'),
+            Some(("de".to_string(), Some("nodeadkeys".to_string())))
+        );
+        let debian = "MODEL=\"pc105\"\nLAYOUT=\"gb\"\nVARIANT=\"\"\nOPTIONS=\"\"\n";
+        assert_eq!(
+            parse_xkb_settings(debian, '='),
+            Some(("gb".to_string(), None))
+        );
+    }
+}
@@ -1,7 +1,6 @@
 use anyhow::anyhow;
 use eframe::egui;
 use ironrdp::connector::{self, Credentials};
-use ironrdp::dvc::{encode_dvc_messages, DrdynvcClient, DvcEncode};
 use ironrdp::pdu::input::fast_path::FastPathInputEvent;
 use ironrdp::pdu::input::mouse::PointerFlags;
 use ironrdp::pdu::input::MousePdu;
@@ -9,31 +8,60 @@ use ironrdp::pdu::rdp::capability_sets::MajorPlatformType;
 use ironrdp::pdu::rdp::client_info::PerformanceFlags;
 use ironrdp::session::image::DecodedImage;
 use ironrdp::session::{ActiveStage, ActiveStageOutput};
-use ironrdp::svc::{ChannelFlags, SvcProcessorMessages};
 use ironrdp_tokio::{split_tokio_framed, FramedWrite};
-use log::{debug, info};
+use log::debug;
 use std::sync::{Arc, Mutex};
-use tokio::net::TcpStream;
-use vc::{GenericChannel, GenericChannelMessage};
 
+pub mod address;
+pub mod cert;
+pub mod combo;
+pub mod finalize;
+pub mod gateway;
 pub mod keyboard;
+pub mod layout;
+pub mod mouse;
 mod network_client;
+pub mod proxy;
+mod restricted_admin;
+pub mod scancode;
+pub mod smartcard;
+pub mod ssh;
 pub mod vc;
 
-type UpgradedFramed = ironrdp_tokio::TokioFramed<ironrdp_tls::TlsStream<TcpStream>>;
+/// The connection to the server, direct or through a gateway.
+pub trait RDPStream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync {}
+
+impl<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync> RDPStream for S {}
+
+type UpgradedFramed = ironrdp_tokio::TokioFramed<ironrdp_tls::TlsStream<Box<dyn RDPStream>>>;
 
 pub struct RDPSession {
-    width: u16,
-    height: u16,
     config: connector::Config,
     dynamic_virtual_channels: Option<Vec<String>>,
+    gateway: Option<gateway::RDPGateway>,
+    proxy: Option<proxy::RDPProxy>,
+    ssh_tunnel: Option<ssh::RDPSshTunnel>,
+    certificate_verifier: cert::RDPCertificateVerifier,
+    server_name: Option<String>,
+    security: RDPSecurity,
+    kerberos_config: Option<connector::credssp::KerberosConfig>,
+    kerberos: RDPKerberos,
+    restricted_admin: bool,
 }
 
 // TODO be nice to have a builder pattern and default port (viz. 3389)
-pub struct RDPCredentials {
-    username: String,
-    password: String,
-    domain: Option<String>,
+pub enum RDPCredentials {
+    Password {
+        username: String,
+        password: String,
+        domain: Option<String>,
+    },
+    /// Logon with a certificate, the user being the one it names.
+    SmartCard {
+        pin: String,
+        card: smartcard::RDPSmartCard,
+        domain: Option<String>,
+    },
 }
 
 #[derive(Default, Clone)]
@@ -42,6 +70,193 @@ pub struct RDPMousePosition {
     pub y: u16,
 }
 
+/// Pointer changes from the server, which are only reported when the pointer
+/// is not being composited into the framebuffer.
+pub enum RDPPointerUpdate {
+    Default,
+    Hidden,
+    Position { x: u16, y: u16 },
+    Bitmap(RDPPointerBitmap),
+}
+
+pub struct RDPPointerBitmap {
+    pub width: u16,
+    pub height: u16,
+    pub hotspot_x: u16,
+    pub hotspot_y: u16,
+    /// RGBA with non-premultiplied alpha.
+    pub data: Vec<u8>,
+}
+
+/// Smallest and largest desktop width or height a server accepts.
+const RESOLUTION_RANGE: std::ops::RangeInclusive<u16> = 200..=8192;
+
+/// Desktop size to ask the server for, written as `WIDTHxHEIGHT`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RDPResolution {
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Default for RDPResolution {
+    fn default() -> Self {
+        Self {
+            width: 1024,
+            height: 768,
+        }
+    }
+}
+
+impl std::str::FromStr for RDPResolution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s
+            .split_once(['x', 'X'])
+            .ok_or_else(|| anyhow!("Expected WIDTHxHEIGHT, got '{}'", s))?;
+        let (width, height): (u16, u16) = (width.trim().parse()?, height.trim().parse()?);
+        if !RESOLUTION_RANGE.contains(&width) || !RESOLUTION_RANGE.contains(&height) {
+            return Err(anyhow!(
+                "Width and height must be between {} and {}, got '{}'",
+                RESOLUTION_RANGE.start(),
+                RESOLUTION_RANGE.end(),
+                s
+            ));
+        }
+        Ok(Self { width, height })
+    }
+}
+
+/// Desktop features which the server may turn off (or on) to save bandwidth.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum RDPPerformanceFlag {
+    DisableWallpaper,
+    DisableFullWindowDrag,
+    DisableMenuAnimations,
+    DisableTheming,
+    DisableCursorShadow,
+    DisableCursorSettings,
+    EnableFontSmoothing,
+    EnableDesktopComposition,
+    None,
+}
+
+impl RDPPerformanceFlag {
+    fn flags(self) -> PerformanceFlags {
+        match self {
+            RDPPerformanceFlag::DisableWallpaper => PerformanceFlags::DISABLE_WALLPAPER,
+            RDPPerformanceFlag::DisableFullWindowDrag => PerformanceFlags::DISABLE_FULLWINDOWDRAG,
+            RDPPerformanceFlag::DisableMenuAnimations => PerformanceFlags::DISABLE_MENUANIMATIONS,
+            RDPPerformanceFlag::DisableTheming => PerformanceFlags::DISABLE_THEMING,
+            RDPPerformanceFlag::DisableCursorShadow => PerformanceFlags::DISABLE_CURSOR_SHADOW,
+            RDPPerformanceFlag::DisableCursorSettings => PerformanceFlags::DISABLE_CURSORSETTINGS,
+            RDPPerformanceFlag::EnableFontSmoothing => PerformanceFlags::ENABLE_FONT_SMOOTHING,
+            RDPPerformanceFlag::EnableDesktopComposition => {
+                PerformanceFlags::ENABLE_DESKTOP_COMPOSITION
+            }
+            RDPPerformanceFlag::None => PerformanceFlags::empty(),
+        }
+    }
+}
+
+/// Security protocols to offer the server.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum RDPSecurity {
+    /// TLS or NLA, as the server prefers.
+    #[default]
+    Auto,
+    /// TLS without NLA, logging in at the server's login screen.
+    Tls,
+    /// Network Level Authentication using CredSSP.
+    Nla,
+    /// Standard RDP security, which IronRDP doesn't support.
+    Rdp,
+}
+
+impl std::fmt::Display for RDPSecurity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use clap::ValueEnum;
+        match self.to_possible_value() {
+            Some(value) => write!(f, "{}", value.get_name()),
+            None => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// Kerberos settings for NLA, which otherwise uses NTLM.
+#[derive(Clone, Debug)]
+pub struct RDPKerberos {
+    /// `tcp://`, `udp://` or, for a KDC proxy, `https://`. Found through DNS if not given.
+    pub kdc_url: Option<url::Url>,
+    /// Log in as `user@REALM` rather than `DOMAIN\user`.
+    pub realm: Option<String>,
+    /// This computer's name, as given to the KDC.
+    pub hostname: Option<String>,
+    /// How long to wait for each reply from the KDC.
+    pub timeout: std::time::Duration,
+    /// PEM file of extra CA certificates to trust for a KDC proxy.
+    pub proxy_ca: Option<std::path::PathBuf>,
+    /// Accept any certificate from a KDC proxy.
+    pub proxy_insecure: bool,
+}
+
+impl Default for RDPKerberos {
+    fn default() -> Self {
+        Self {
+            kdc_url: None,
+            realm: None,
+            hostname: None,
+            timeout: std::time::Duration::from_secs(10),
+            proxy_ca: None,
+            proxy_insecure: false,
+        }
+    }
+}
+
+/// Parse a KDC address, which is taken to be `tcp://` if it has no scheme.
+pub fn parse_kdc_url(s: &str) -> anyhow::Result<url::Url> {
+    let url = if s.contains("://") {
+        url::Url::parse(s)?
+    } else {
+        url::Url::parse(&format!("tcp://{}", s))?
+    };
+    match url.scheme() {
+        "tcp" | "udp" | "http" | "https" => Ok(url),
+        scheme => Err(anyhow!(
+            "Unsupported KDC scheme '{}'; expected tcp, udp, http or https",
+            scheme
+        )),
+    }
+}
+
+/// Explain a failed security negotiation in terms of `--security`.
+fn negotiation_error(security: RDPSecurity, e: connector::ConnectorError) -> anyhow::Error {
+    use ironrdp::pdu::nego::FailureCode;
+
+    let message = e.to_string();
+    let failed = |code: FailureCode| message.contains(&code.to_string());
+    // Checked first, as the TLS message is part of this one.
+    let hint = if failed(FailureCode::HYBRID_REQUIRED_BY_SERVER) {
+        "the server requires NLA, so use --security nla or auto".to_string()
+    } else if failed(FailureCode::SSL_REQUIRED_BY_SERVER) {
+        "the server doesn't allow NLA, so use --security tls or auto".to_string()
+    } else if failed(FailureCode::SSL_NOT_ALLOWED_BY_SERVER)
+        || message.contains("standard RDP security is not supported")
+    {
+        "standard RDP security isn't supported; the server must allow TLS or NLA".to_string()
+    } else if failed(FailureCode::SSL_CERT_NOT_ON_SERVER) {
+        "the server has no certificate for TLS".to_string()
+    } else if message.contains("but server selected") {
+        format!(
+            "the server chose a protocol which --security {} doesn't allow",
+            security
+        )
+    } else {
+        return anyhow!(e);
+    };
+    anyhow!("Security negotiation failed: {} ({})", hint, message)
+}
+
 #[derive(Default)]
 pub struct RDPSharedFramebuffer {
     pub image: Option<Vec<u8>>,
@@ -51,26 +266,39 @@ pub struct RDPSharedFramebuffer {
 
 impl RDPCredentials {
     pub fn new(username: String, password: String, domain: Option<String>) -> Self {
-        Self {
+        Self::Password {
             username,
             password,
             domain,
         }
     }
+
+    pub fn smart_card(pin: String, card: smartcard::RDPSmartCard, domain: Option<String>) -> Self {
+        Self::SmartCard { pin, card, domain }
+    }
 }
 
 impl RDPSession {
     pub fn from_credentials(credentials: RDPCredentials) -> Self {
-        // TODO hard coded default screen size
-        let width = 1024;
-        let height = 768;
+        let RDPResolution { width, height } = RDPResolution::default();
 
+        let (credentials, domain) = match credentials {
+            RDPCredentials::Password {
+                username,
+                password,
+                domain,
+            } => (Credentials::UsernamePassword { username, password }, domain),
+            RDPCredentials::SmartCard { pin, card, domain } => (
+                Credentials::SmartCard {
+                    pin,
+                    config: Some(card.identity()),
+                },
+                domain,
+            ),
+        };
         let config = connector::Config {
-            credentials: Credentials::UsernamePassword {
-                username: credentials.username,
-                password: credentials.password,
-            },
-            domain: credentials.domain,
+            credentials,
+            domain,
             enable_tls: true,
             enable_credssp: true,
             keyboard_type: ironrdp::pdu::gcc::KeyboardType::IbmEnhanced,
@@ -96,15 +324,22 @@ impl RDPSession {
             no_server_pointer: false,
             autologon: false,
             request_data: None,
-            pointer_software_rendering: true,
+            pointer_software_rendering: false,
             performance_flags: PerformanceFlags::DISABLE_FULLWINDOWDRAG,
         };
 
         Self {
-            width,
-            height,
             config,
             dynamic_virtual_channels: None,
+            certificate_verifier: Default::default(),
+            server_name: None,
+            security: RDPSecurity::Auto,
+            kerberos_config: None,
+            kerberos: RDPKerberos::default(),
+            restricted_admin: false,
+            gateway: None,
+            proxy: None,
+            ssh_tunnel: None,
         }
     }
 
@@ -113,17 +348,154 @@ impl RDPSession {
         self
     }
 
+    pub fn with_resolution(mut self, resolution: RDPResolution) -> Self {
+        self.config.desktop_size = connector::DesktopSize {
+            width: resolution.width,
+            height: resolution.height,
+        };
+        self
+    }
+
+    /// Replace the default performance flags, unless none are given.
+    pub fn with_performance_flags(mut self, flags: &[RDPPerformanceFlag]) -> Self {
+        if !flags.is_empty() {
+            self.config.performance_flags = flags
+                .iter()
+                .fold(PerformanceFlags::empty(), |all, flag| all | flag.flags());
+        }
+        self
+    }
+
+    /// Have the server's pointer composited into the framebuffer instead of being
+    /// drawn by the GUI.
+    pub fn with_software_pointer(mut self, software_pointer: bool) -> Self {
+        self.config.pointer_software_rendering = software_pointer;
+        self
+    }
+
+    /// Ask the server to use the given input locale rather than its own default.
+    pub fn with_keyboard_layout(mut self, layout: Option<layout::KeyboardLayout>) -> Self {
+        if let Some(layout) = layout {
+            self.config.keyboard_layout = layout.klid;
+            // Japanese layouts expect the 106/109 key keyboard's extra keys.
+            if layout.klid & 0xffff == 0x0411 {
+                self.config.keyboard_type = ironrdp::pdu::gcc::KeyboardType::Japanese;
+                self.config.keyboard_subtype = 2;
+            }
+        }
+        self
+    }
+
+    pub fn with_certificate_verifier(mut self, verifier: cert::RDPCertificateVerifier) -> Self {
+        self.certificate_verifier = verifier;
+        self
+    }
+
+    pub fn with_security(mut self, security: RDPSecurity) -> Self {
+        (self.config.enable_tls, self.config.enable_credssp) = match security {
+            RDPSecurity::Auto => (true, true),
+            RDPSecurity::Tls => (true, false),
+            RDPSecurity::Nla => (false, true),
+            RDPSecurity::Rdp => (false, false),
+        };
+        self.security = security;
+        self
+    }
+
+    /// Use Kerberos for NLA, if given.
+    pub fn with_kerberos(mut self, kerberos: Option<RDPKerberos>) -> Self {
+        let Some(kerberos) = kerberos else {
+            return self;
+        };
+        if let (Some(realm), Credentials::UsernamePassword { username, .. }) =
+            (&kerberos.realm, &mut self.config.credentials)
+        {
+            *username = format!("{}@{}", username, realm.to_uppercase());
+            self.config.domain = None;
+        }
+        self.kerberos_config = Some(connector::credssp::KerberosConfig {
+            kdc_proxy_url: kerberos.kdc_url.clone(),
+            hostname: kerberos.hostname.clone(),
+        });
+        self.kerberos = kerberos;
+        self
+    }
+
+    /// Log on without giving the server the credentials (Restricted Admin mode),
+    /// which needs NLA and a password.
+    pub fn with_restricted_admin(mut self, restricted_admin: bool) -> Self {
+        self.restricted_admin = restricted_admin;
+        self
+    }
+
+    /// Reach the server through an RD Gateway.
+    pub fn with_gateway(mut self, gateway: Option<gateway::RDPGateway>) -> Self {
+        self.gateway = gateway;
+        self
+    }
+
+    /// Make the connection, and any to the KDC, through a proxy.
+    pub fn with_proxy(mut self, proxy: Option<proxy::RDPProxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Make the connection through an SSH jump host.
+    pub fn with_ssh_tunnel(mut self, ssh_tunnel: Option<ssh::RDPSshTunnel>) -> Self {
+        self.ssh_tunnel = ssh_tunnel;
+        self
+    }
+
+    /// Use this name, rather than the host connected to, for TLS SNI, the certificate
+    /// check and CredSSP's service principal name.
+    pub fn with_server_name(mut self, server_name: Option<String>) -> Self {
+        self.server_name = server_name;
+        self
+    }
+
     pub async fn connect(
         &self,
-        host: &str,
+        host: &address::RDPHost,
         port: u16,
-    ) -> anyhow::Result<(connector::ConnectionResult, UpgradedFramed)> {
-        let stream = TcpStream::connect(format!("{}:{}", host, port))
-            .await
-            .map_err(|e| connector::custom_err!("TCP Connection to RDP Server", e))?;
-        let addr = stream
-            .peer_addr()
-            .map_err(|e| connector::custom_err!("Getting RDP Server address", e))?;
+    ) -> anyhow::Result<(
+        connector::ConnectionResult,
+        finalize::RDPServerInput,
+        UpgradedFramed,
+    )> {
+        let server_name = self.server_name.clone().unwrap_or_else(|| host.name());
+        let (stream, addr): (Box<dyn RDPStream>, _) = match (&self.gateway, &self.ssh_tunnel) {
+            (Some(gateway), _) => {
+                let (stream, addr) = gateway
+                    .connect(
+                        &host.name(),
+                        port,
+                        &self.config.client_name,
+                        self.proxy.as_ref(),
+                    )
+                    .await?;
+                (Box::new(stream), addr)
+            }
+            (None, Some(ssh_tunnel)) => {
+                let stream = ssh_tunnel.connect(&host.name(), port).await?;
+                // The server's address isn't known from here.
+                let addr = std::net::SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, port));
+                (Box::new(stream), addr)
+            }
+            (None, None) => {
+                let stream = proxy::connect(self.proxy.as_ref(), &host.name(), port)
+                    .await
+                    .map_err(|e| {
+                        connector::custom_err!(
+                            "TCP Connection to RDP Server",
+                            std::io::Error::other(e)
+                        )
+                    })?;
+                let addr = stream
+                    .peer_addr()
+                    .map_err(|e| connector::custom_err!("Getting RDP Server address", e))?;
+                (Box::new(stream), addr)
+            }
+        };
 
         let mut framed = ironrdp_tokio::TokioFramed::new(stream);
 
@@ -136,32 +508,58 @@ impl RDPSession {
             .with_server_addr(addr)
             .with_static_channel(dynamic_channels);
 
-        let should_upgrade = ironrdp_tokio::connect_begin(&mut framed, &mut connector).await?;
+        let should_upgrade = if self.restricted_admin {
+            restricted_admin::connect_begin(&mut framed, &mut connector).await
+        } else {
+            ironrdp_tokio::connect_begin(&mut framed, &mut connector).await
+        }
+        .map_err(|e| negotiation_error(self.security, e))?;
         let initial_stream = framed.into_inner_no_leftover();
 
-        // Not sure 'server name' is always OK here, given port number suffix?
-        // TODO In fact it seems to be very much not OK!
-        let (upgraded_stream, server_public_key) = ironrdp_tls::upgrade(initial_stream, &host)
-            .await
-            .map_err(|e| connector::custom_err!("RDP TLS Upgrade", e))?;
+        let (upgraded_stream, server_public_key) =
+            ironrdp_tls::upgrade(initial_stream, &server_name)
+                .await
+                .map_err(|e| connector::custom_err!("RDP TLS Upgrade", e))?;
+        // The upgrade itself accepts any certificate.
+        let certificates = upgraded_stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .unwrap_or_default();
+        self.certificate_verifier
+            .verify(&server_name, port, certificates)
+            .await?;
         let upgraded = ironrdp_tokio::mark_as_upgraded(should_upgrade, &mut connector);
 
         let mut upgraded_framed = ironrdp_tokio::TokioFramed::new(upgraded_stream);
 
-        let mut network_client = network_client::ReqwestNetworkClient::new();
+        let mut network_client = network_client::ReqwestNetworkClient::new(&self.kerberos)
+            .with_proxy(self.proxy.clone())
+            .with_ssh_tunnel(self.ssh_tunnel.clone());
+        if self.restricted_admin {
+            restricted_admin::perform_credssp(
+                &mut upgraded_framed,
+                &mut connector,
+                &server_name,
+                server_public_key.clone(),
+                &mut network_client,
+                self.kerberos_config.clone(),
+            )
+            .await?;
+        }
 
-        let connection_result = ironrdp_tokio::connect_finalize(
+        let (connection_result, server_input) = finalize::connect_finalize(
             upgraded,
             &mut upgraded_framed,
             connector,
-            host.into(),
+            server_name.into(),
             server_public_key,
-            Some(&mut network_client),
-            None,
+            &mut network_client,
+            self.kerberos_config.clone(),
         )
         .await?;
 
-        Ok((connection_result, upgraded_framed))
+        Ok((connection_result, server_input, upgraded_framed))
     }
 
     pub async fn session_thread(
@@ -170,7 +568,8 @@ impl RDPSession {
         tx: tokio::sync::watch::Sender<Arc<Mutex<RDPSharedFramebuffer>>>,
         mut mouse_rx: tokio::sync::watch::Receiver<RDPMousePosition>,
         mut rdp_input_rx: tokio::sync::mpsc::Receiver<Vec<FastPathInputEvent>>,
-        rctx: tokio::sync::oneshot::Receiver<egui::Context>,
+        pointer_tx: tokio::sync::mpsc::UnboundedSender<RDPPointerUpdate>,
+        egui_ctx: egui::Context,
     ) -> anyhow::Result<()> {
         let (mut reader, mut writer) = split_tokio_framed(framed);
 
@@ -183,9 +582,14 @@ impl RDPSession {
         );
         let mut active_stage = ActiveStage::new(connection_result);
 
-        let egui_ctx = rctx.await?;
-        info!("RDP session waiting for GUI context");
         let shared_frame_buffer = tx.borrow().clone();
+        let update_pointer = |update: RDPPointerUpdate| -> anyhow::Result<()> {
+            pointer_tx
+                .send(update)
+                .map_err(|_| anyhow!("Pointer update channel has closed"))?;
+            egui_ctx.request_repaint();
+            Ok(())
+        };
         loop {
             let outputs = tokio::select! {
                 biased; // make sure we mouse events aren't overwhelming
@@ -196,7 +600,7 @@ impl RDPSession {
                 changed = mouse_rx.changed() => match changed {
                     Ok(()) => {
                         let p = mouse_rx.borrow().clone();
-                        active_stage.process_fastpath_input(&mut image,&vec![FastPathInputEvent::MouseEvent(MousePdu{x_position: p.x, y_position: p.y, flags: PointerFlags::MOVE, number_of_wheel_rotation_units: 0})] )?
+                        active_stage.process_fastpath_input(&mut image, &[FastPathInputEvent::MouseEvent(MousePdu{x_position: p.x, y_position: p.y, flags: PointerFlags::MOVE, number_of_wheel_rotation_units: 0})])?
                     },
                     Err(_) => return Err(anyhow!("Mouse position channel has closed")),
                 },
@@ -229,6 +633,20 @@ impl RDPSession {
                         }
                         egui_ctx.request_repaint();
                     }
+                    ActiveStageOutput::PointerDefault => update_pointer(RDPPointerUpdate::Default)?,
+                    ActiveStageOutput::PointerHidden => update_pointer(RDPPointerUpdate::Hidden)?,
+                    ActiveStageOutput::PointerPosition { x, y } => {
+                        update_pointer(RDPPointerUpdate::Position { x, y })?
+                    }
+                    ActiveStageOutput::PointerBitmap(pointer) => {
+                        update_pointer(RDPPointerUpdate::Bitmap(RDPPointerBitmap {
+                            width: pointer.width,
+                            height: pointer.height,
+                            hotspot_x: pointer.hotspot_x,
+                            hotspot_y: pointer.hotspot_y,
+                            data: pointer.bitmap_data.clone(),
+                        }))?
+                    }
                     ActiveStageOutput::Terminate(reason) => {
                         return Err(anyhow!("RDP Session terminated with reason: {}", reason));
                     }
@@ -240,3 +658,115 @@ impl RDPSession {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ironrdp::pdu::nego::FailureCode;
+
+    #[test]
+    fn resolutions() {
+        assert_eq!(
+            "1280x800".parse::<RDPResolution>().unwrap(),
+            RDPResolution {
+                width: 1280,
+                height: 800
+            }
+        );
+        assert!("200X8192".parse::<RDPResolution>().is_ok());
+        assert!("199x600".parse::<RDPResolution>().is_err());
+        assert!("800x8193".parse::<RDPResolution>().is_err());
+        assert!("800".parse::<RDPResolution>().is_err());
+    }
+
+    #[test]
+    fn kdc_urls() {
+        assert_eq!(
+            parse_kdc_url("kdc.lab.example:88").unwrap().as_str(),
+            "tcp://kdc.lab.example:88"
+        );
+        assert_eq!(parse_kdc_url("udp://kdc").unwrap().scheme(), "udp");
+        assert!(parse_kdc_url("ftp://kdc").is_err());
+    }
+
+    #[tokio::test]
+    async fn kerberos_asks_configured_kdc() {
+        use ironrdp::connector::credssp::CredsspSequence;
+        use ironrdp_tokio::AsyncNetworkClient;
+        use sspi::generator::GeneratorState;
+        use tokio::io::AsyncReadExt;
+
+        // Stands in for a KDC, keeping the AS-REQ and then hanging up.
+        let kdc = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let kdc_url = parse_kdc_url(&kdc.local_addr().unwrap().to_string()).unwrap();
+        let stand_in = tokio::spawn(async move {
+            let (mut stream, _) = kdc.accept().await.unwrap();
+            let len = stream.read_u32().await.unwrap();
+            let mut request = vec![0; len as usize];
+            stream.read_exact(&mut request).await.unwrap();
+            request
+        });
+
+        let credentials = RDPCredentials::new("tester".into(), "secret".into(), Some("LAB".into()));
+        let session = RDPSession::from_credentials(credentials).with_kerberos(Some(RDPKerberos {
+            kdc_url: Some(kdc_url),
+            realm: Some("lab.example".into()),
+            hostname: Some("client1".into()),
+            ..Default::default()
+        }));
+        let (mut sequence, ts_request) = CredsspSequence::init(
+            session.config.credentials.clone(),
+            session.config.domain.as_deref(),
+            ironrdp::pdu::nego::SecurityProtocol::HYBRID,
+            "vm1.lab.example".into(),
+            vec![0; 32],
+            session.kerberos_config.clone(),
+        )
+        .unwrap();
+        // The first round only offers Kerberos to the server. Given an empty reply,
+        // the second asks the KDC for a ticket.
+        let mut network_client = network_client::ReqwestNetworkClient::new(&session.kerberos);
+        let empty_reply = sspi::credssp::TsRequest {
+            nego_tokens: Some(Vec::new()),
+            ..Default::default()
+        };
+        for ts_request in [ts_request, empty_reply] {
+            let mut generator = sequence.process_ts_request(ts_request);
+            let mut state = generator.start();
+            while let GeneratorState::Suspended(request) = state {
+                let response = network_client.send(&request).await.map_err(|e| {
+                    sspi::Error::new(sspi::ErrorKind::NoAuthenticatingAuthority, e.to_string())
+                });
+                state = generator.resume(response);
+            }
+        }
+
+        let request = tokio::time::timeout(std::time::Duration::from_secs(10), stand_in)
+            .await
+            .expect("No request reached the KDC")
+            .unwrap();
+        let contains = |s: &str| request.windows(s.len()).any(|w| w == s.as_bytes());
+        assert!(contains("LAB.EXAMPLE"));
+        assert!(contains("tester"));
+        assert!(contains("krbtgt"));
+    }
+
+    #[test]
+    fn negotiation_failures_explained() {
+        let failure = |code: FailureCode| {
+            negotiation_error(
+                RDPSecurity::Tls,
+                connector::reason_err!("Initiation", "{code}"),
+            )
+            .to_string()
+        };
+        assert!(failure(FailureCode::HYBRID_REQUIRED_BY_SERVER).contains("--security nla"));
+        assert!(failure(FailureCode::SSL_REQUIRED_BY_SERVER).contains("--security tls"));
+        assert!(failure(FailureCode::SSL_NOT_ALLOWED_BY_SERVER).contains("standard RDP security"));
+
+        let other = connector::reason_err!("Initiation", "something else");
+        assert!(!negotiation_error(RDPSecurity::Auto, other)
+            .to_string()
+            .contains("Security negotiation failed"));
+    }
+}
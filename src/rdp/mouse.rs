@@ -0,0 +1,436 @@
+use eframe::egui::{Event, MouseWheelUnit, PointerButton, Pos2, Rect, Vec2};
+use ironrdp::pdu::input::fast_path::FastPathInputEvent;
+use ironrdp::pdu::input::mouse::PointerFlags;
+use ironrdp::pdu::input::mouse_rel::PointerRelFlags;
+use ironrdp::pdu::input::mouse_x::PointerXFlags;
+use ironrdp::pdu::input::{MousePdu, MouseRelPdu, MouseXPdu};
+
+/// One notch of a conventional mouse wheel, per WHEEL_DELTA in winuser.h
+pub static WHEEL_DELTA: i16 = 120;
+
+/// How many egui points of smooth (e.g. trackpad) scrolling make up one wheel notch.
+/// Matches egui's own native line scroll speed.
+static POINTS_PER_WHEEL_NOTCH: f32 = 40.0;
+
+pub struct RDPMouseEvents {
+    fastpath_events: Vec<FastPathInputEvent>,
+}
+
+impl RDPMouseEvents {
+    pub fn into_fastpath_events(self) -> Vec<FastPathInputEvent> {
+        self.fastpath_events
+    }
+}
+
+/// How pointer movement is reported to the server while the local cursor is captured.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RDPPointerCapture {
+    /// Not captured; the local cursor position is sent as-is.
+    #[default]
+    Released,
+    /// Raw mouse motion is sent as relative mouse events, which the server must support.
+    Relative,
+    /// Raw mouse motion is accumulated into an absolute position for servers which
+    /// cannot accept relative mouse events.
+    Synthesized,
+}
+
+/// Tracks the pointer state we have reported to the server, so that wheel events
+/// can be given a position, fractional scrolling can be accumulated, and any buttons
+/// held down during a drag can be released if the window loses focus.
+#[derive(Default)]
+pub struct RDPMouseState {
+    x: u16,
+    y: u16,
+    buttons_down: Vec<PointerButton>,
+    wheel_remainder: Vec2,
+    capture: RDPPointerCapture,
+}
+
+impl RDPMouseState {
+    pub fn capture(&self) -> RDPPointerCapture {
+        self.capture
+    }
+
+    /// The last absolute position reported to the server.
+    pub fn position(&self) -> (u16, u16) {
+        (self.x, self.y)
+    }
+
+    /// Change the capture mode, releasing any held buttons since their press was
+    /// reported in terms of the previous mode.
+    pub fn set_capture(&mut self, capture: RDPPointerCapture) -> Vec<FastPathInputEvent> {
+        let events = self.release_all();
+        self.capture = capture;
+        events
+    }
+
+    pub fn maybe_from(&mut self, event: &Event, bounds: Rect) -> Option<RDPMouseEvents> {
+        let fastpath_events = match event {
+            Event::PointerMoved(pos) => {
+                // Movement itself is reported via the mouse position channel; we only
+                // need to know where the pointer is for any subsequent wheel events.
+                // While captured the local cursor is pinned, so its position is meaningless.
+                if self.capture == RDPPointerCapture::Released {
+                    (self.x, self.y) = clamp_position(*pos, bounds);
+                }
+                return None;
+            }
+            Event::MouseMoved(delta) => match self.capture {
+                RDPPointerCapture::Released => return None,
+                RDPPointerCapture::Relative => {
+                    let x_delta = delta.x.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                    let y_delta = delta.y.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                    if x_delta == 0 && y_delta == 0 {
+                        return None;
+                    }
+                    vec![FastPathInputEvent::MouseEventRel(MouseRelPdu {
+                        flags: PointerRelFlags::MOVE,
+                        x_delta,
+                        y_delta,
+                    })]
+                }
+                RDPPointerCapture::Synthesized => {
+                    let pos = Pos2::new(f32::from(self.x), f32::from(self.y)) + delta.round();
+                    let (x, y) = clamp_position(pos, bounds);
+                    if (x, y) == (self.x, self.y) {
+                        return None;
+                    }
+                    (self.x, self.y) = (x, y);
+                    vec![FastPathInputEvent::MouseEvent(MousePdu {
+                        flags: PointerFlags::MOVE,
+                        number_of_wheel_rotation_units: 0,
+                        x_position: x,
+                        y_position: y,
+                    })]
+                }
+            },
+            Event::PointerButton {
+                pos,
+                button,
+                pressed,
+                ..
+            } => {
+                if self.capture == RDPPointerCapture::Released {
+                    (self.x, self.y) = clamp_position(*pos, bounds);
+                }
+                if *pressed {
+                    if self.buttons_down.contains(button) {
+                        return None;
+                    }
+                    self.buttons_down.push(*button);
+                } else {
+                    if !self.buttons_down.contains(button) {
+                        return None;
+                    }
+                    self.buttons_down.retain(|b| b != button);
+                }
+                vec![self.button_event(*button, *pressed)]
+            }
+            Event::MouseWheel { unit, delta, .. } => {
+                let notches = match unit {
+                    MouseWheelUnit::Point => *delta / POINTS_PER_WHEEL_NOTCH,
+                    MouseWheelUnit::Line | MouseWheelUnit::Page => *delta,
+                };
+                self.wheel_remainder += notches * f32::from(WHEEL_DELTA);
+
+                let mut events = Vec::new();
+                // egui considers a positive x delta to be scrolling left, RDP (like Win32) the
+                // opposite; the vertical axes agree that positive is away from the user.
+                let vertical = self.wheel_remainder.y.trunc() as i32;
+                let horizontal = self.wheel_remainder.x.trunc() as i32;
+                self.wheel_remainder.y -= vertical as f32;
+                self.wheel_remainder.x -= horizontal as f32;
+                self.push_wheel_events(&mut events, PointerFlags::VERTICAL_WHEEL, vertical);
+                self.push_wheel_events(&mut events, PointerFlags::HORIZONTAL_WHEEL, -horizontal);
+                events
+            }
+            Event::WindowFocused(false) => self.release_all(),
+            _unsupported => return None,
+        };
+
+        if fastpath_events.is_empty() {
+            None
+        } else {
+            Some(RDPMouseEvents { fastpath_events })
+        }
+    }
+
+    /// Release any buttons still held, e.g. because focus was lost part way through
+    /// a drag and we will never see the button come back up.
+    pub fn release_all(&mut self) -> Vec<FastPathInputEvent> {
+        std::mem::take(&mut self.buttons_down)
+            .into_iter()
+            .map(|button| self.button_event(button, false))
+            .collect()
+    }
+
+    fn button_event(&self, button: PointerButton, pressed: bool) -> FastPathInputEvent {
+        if self.capture == RDPPointerCapture::Relative {
+            // Absolute button events would warp the remote cursor back to a stale position.
+            let mut flags = match button {
+                PointerButton::Primary => PointerRelFlags::BUTTON1,
+                PointerButton::Secondary => PointerRelFlags::BUTTON2,
+                PointerButton::Middle => PointerRelFlags::BUTTON3,
+                PointerButton::Extra1 => PointerRelFlags::XBUTTON1,
+                PointerButton::Extra2 => PointerRelFlags::XBUTTON2,
+            };
+            if pressed {
+                flags |= PointerRelFlags::DOWN;
+            }
+            return FastPathInputEvent::MouseEventRel(MouseRelPdu {
+                flags,
+                x_delta: 0,
+                y_delta: 0,
+            });
+        }
+
+        let flags = match button {
+            PointerButton::Primary => PointerFlags::LEFT_BUTTON,
+            PointerButton::Secondary => PointerFlags::RIGHT_BUTTON,
+            PointerButton::Middle => PointerFlags::MIDDLE_BUTTON_OR_WHEEL,
+            PointerButton::Extra1 | PointerButton::Extra2 => {
+                // The X buttons are only available via the extended mouse event.
+                let mut flags = if button == PointerButton::Extra1 {
+                    PointerXFlags::BUTTON1
+                } else {
+                    PointerXFlags::BUTTON2
+                };
+                if pressed {
+                    flags |= PointerXFlags::DOWN;
+                }
+                return FastPathInputEvent::MouseEventEx(MouseXPdu {
+                    flags,
+                    x_position: self.x,
+                    y_position: self.y,
+                });
+            }
+        };
+
+        FastPathInputEvent::MouseEvent(MousePdu {
+            flags: if pressed {
+                flags | PointerFlags::DOWN
+            } else {
+                flags
+            },
+            number_of_wheel_rotation_units: 0,
+            x_position: self.x,
+            y_position: self.y,
+        })
+    }
+
+    /// The rotation field is a 9-bit signed value, so large deltas are split into
+    /// several events of at most one notch each.
+    fn push_wheel_events(
+        &self,
+        events: &mut Vec<FastPathInputEvent>,
+        axis: PointerFlags,
+        mut rotation: i32,
+    ) {
+        while rotation != 0 {
+            let units = rotation.clamp(-i32::from(WHEEL_DELTA), i32::from(WHEEL_DELTA));
+            rotation -= units;
+            events.push(FastPathInputEvent::MouseEvent(MousePdu {
+                flags: axis,
+                number_of_wheel_rotation_units: units as i16,
+                x_position: self.x,
+                y_position: self.y,
+            }));
+        }
+    }
+}
+
+/// Clamp a GUI pointer position to the bounds of the RDP view.
+pub fn clamp_position(pos: Pos2, bounds: Rect) -> (u16, u16) {
+    let x = f32::min(f32::max(pos.x, bounds.min.x), bounds.max.x) as u16;
+    let y = f32::min(f32::max(pos.y, bounds.min.y), bounds.max.y) as u16;
+    (x, y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eframe::egui::Modifiers;
+
+    fn bounds() -> Rect {
+        Rect::from_min_max(Pos2::ZERO, Pos2::new(1024.0, 768.0))
+    }
+
+    fn events(state: &mut RDPMouseState, event: Event) -> Vec<FastPathInputEvent> {
+        state
+            .maybe_from(&event, bounds())
+            .map(RDPMouseEvents::into_fastpath_events)
+            .unwrap_or_default()
+    }
+
+    fn wheel(delta: Vec2) -> Event {
+        Event::MouseWheel {
+            unit: MouseWheelUnit::Line,
+            delta,
+            modifiers: Modifiers::NONE,
+        }
+    }
+
+    fn button(button: PointerButton, pressed: bool) -> Event {
+        Event::PointerButton {
+            pos: Pos2::new(10.0, 20.0),
+            button,
+            pressed,
+            modifiers: Modifiers::NONE,
+        }
+    }
+
+    fn wheel_units(events: &[FastPathInputEvent]) -> Vec<(PointerFlags, i16)> {
+        events
+            .iter()
+            .map(|event| match event {
+                FastPathInputEvent::MouseEvent(pdu) => {
+                    (pdu.flags, pdu.number_of_wheel_rotation_units)
+                }
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn wheel_split_into_notches() {
+        let mut state = RDPMouseState::default();
+        let vertical = PointerFlags::VERTICAL_WHEEL;
+        assert_eq!(
+            wheel_units(&events(&mut state, wheel(Vec2::new(0.0, 2.5)))),
+            [(vertical, 120), (vertical, 120), (vertical, 60)]
+        );
+        assert_eq!(
+            wheel_units(&events(&mut state, wheel(Vec2::new(0.0, -1.0)))),
+            [(vertical, -120)]
+        );
+
+        // Smooth scrolling accumulates until it adds up to whole units.
+        let smooth = |delta| Event::MouseWheel {
+            unit: MouseWheelUnit::Point,
+            delta,
+            modifiers: Modifiers::NONE,
+        };
+        assert_eq!(
+            wheel_units(&events(&mut state, smooth(Vec2::new(0.0, 0.2)))),
+            []
+        );
+        assert_eq!(
+            wheel_units(&events(&mut state, smooth(Vec2::new(0.0, 0.2)))),
+            [(vertical, 1)]
+        );
+    }
+
+    #[test]
+    fn horizontal_wheel_negated() {
+        let mut state = RDPMouseState::default();
+        let horizontal = PointerFlags::HORIZONTAL_WHEEL;
+        // egui's positive x is scrolling left, which RDP has as negative.
+        assert_eq!(
+            wheel_units(&events(&mut state, wheel(Vec2::new(1.5, 0.0)))),
+            [(horizontal, -120), (horizontal, -60)]
+        );
+        assert_eq!(
+            wheel_units(&events(&mut state, wheel(Vec2::new(-1.0, 0.0)))),
+            [(horizontal, 120)]
+        );
+    }
+
+    #[test]
+    fn x_buttons_use_extended_events() {
+        let mut state = RDPMouseState::default();
+        assert_eq!(
+            events(&mut state, button(PointerButton::Extra1, true)),
+            [FastPathInputEvent::MouseEventEx(MouseXPdu {
+                flags: PointerXFlags::BUTTON1 | PointerXFlags::DOWN,
+                x_position: 10,
+                y_position: 20,
+            })]
+        );
+        assert_eq!(events(&mut state, button(PointerButton::Extra2, false)), []);
+        events(&mut state, button(PointerButton::Extra2, true));
+        assert_eq!(
+            events(&mut state, button(PointerButton::Extra2, false)),
+            [FastPathInputEvent::MouseEventEx(MouseXPdu {
+                flags: PointerXFlags::BUTTON2,
+                x_position: 10,
+                y_position: 20,
+            })]
+        );
+        assert_eq!(
+            events(&mut state, button(PointerButton::Primary, true)),
+            [FastPathInputEvent::MouseEvent(MousePdu {
+                flags: PointerFlags::LEFT_BUTTON | PointerFlags::DOWN,
+                number_of_wheel_rotation_units: 0,
+                x_position: 10,
+                y_position: 20,
+            })]
+        );
+    }
+
+    #[test]
+    fn relative_moves_send_deltas() {
+        let mut state = RDPMouseState::default();
+        assert_eq!(
+            events(&mut state, Event::MouseMoved(Vec2::new(3.0, -2.0))),
+            []
+        );
+
+        state.set_capture(RDPPointerCapture::Relative);
+        assert_eq!(
+            events(&mut state, Event::MouseMoved(Vec2::new(3.4, -2.6))),
+            [FastPathInputEvent::MouseEventRel(MouseRelPdu {
+                flags: PointerRelFlags::MOVE,
+                x_delta: 3,
+                y_delta: -3,
+            })]
+        );
+        // Motion too small to round to a whole unit is dropped.
+        assert_eq!(
+            events(&mut state, Event::MouseMoved(Vec2::new(0.3, 0.4))),
+            []
+        );
+        assert_eq!(
+            events(&mut state, button(PointerButton::Primary, true)),
+            [FastPathInputEvent::MouseEventRel(MouseRelPdu {
+                flags: PointerRelFlags::BUTTON1 | PointerRelFlags::DOWN,
+                x_delta: 0,
+                y_delta: 0,
+            })]
+        );
+    }
+
+    #[test]
+    fn synthesized_moves_stay_in_view() {
+        let mut state = RDPMouseState::default();
+        events(&mut state, Event::PointerMoved(Pos2::new(100.0, 100.0)));
+        state.set_capture(RDPPointerCapture::Synthesized);
+        assert_eq!(
+            events(&mut state, Event::MouseMoved(Vec2::new(20.0, -30.0))),
+            [FastPathInputEvent::MouseEvent(MousePdu {
+                flags: PointerFlags::MOVE,
+                number_of_wheel_rotation_units: 0,
+                x_position: 120,
+                y_position: 70,
+            })]
+        );
+        // While captured, the pinned local cursor doesn't move the remote one.
+        events(&mut state, Event::PointerMoved(Pos2::new(500.0, 500.0)));
+        assert_eq!(state.position(), (120, 70));
+
+        assert_eq!(
+            events(&mut state, Event::MouseMoved(Vec2::new(-500.0, 0.0))),
+            [FastPathInputEvent::MouseEvent(MousePdu {
+                flags: PointerFlags::MOVE,
+                number_of_wheel_rotation_units: 0,
+                x_position: 0,
+                y_position: 70,
+            })]
+        );
+        // Already at the edge, so nothing changes.
+        assert_eq!(
+            events(&mut state, Event::MouseMoved(Vec2::new(-5.0, 0.0))),
+            []
+        );
+    }
+}
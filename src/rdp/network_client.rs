@@ -1,17 +1,37 @@
 use core::future::Future;
 use core::pin::Pin;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::time::Duration;
 
-use ironrdp::connector::{ConnectorResult, custom_err};
+use ironrdp::connector::{custom_err, general_err, ConnectorResult};
 use ironrdp_tokio::AsyncNetworkClient;
+use picky_krb::constants::error_codes::KRB_ERR_RESPONSE_TOO_BIG;
+use picky_krb::messages::{AsReq, KdcProxyMessage, KrbError, TgsReq};
 use reqwest::Client;
+use sspi::network_client::NetworkProtocol;
 use sspi::{Error, ErrorKind};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::UdpSocket;
 use url::Url;
 
+/// Largest reply accepted from a KDC over TCP.
+const MAX_KDC_REPLY_LEN: u32 = 1 << 20;
+
+/// How many times a UDP request is sent before giving up.
+const UDP_ATTEMPTS: u32 = 3;
+
 pub(crate) struct ReqwestNetworkClient {
     client: Option<Client>,
+    timeout: Duration,
+    /// Whether the user named the KDC, in which case no others are tried.
+    kdc_given: bool,
+    srv_kdcs: Option<Vec<Url>>,
+    working_kdc: Option<Url>,
+    proxy_ca: Option<PathBuf>,
+    proxy_insecure: bool,
+    proxy: Option<super::proxy::RDPProxy>,
+    ssh_tunnel: Option<super::ssh::RDPSshTunnel>,
 }
 
 impl AsyncNetworkClient for ReqwestNetworkClient {
@@ -21,14 +41,11 @@ impl AsyncNetworkClient for ReqwestNetworkClient {
     ) -> Pin<Box<dyn Future<Output = ConnectorResult<Vec<u8>>> + 'a>> {
         Box::pin(async move {
             match &request.protocol {
-                sspi::network_client::NetworkProtocol::Tcp => {
-                    self.send_tcp(&request.url, &request.data).await
+                NetworkProtocol::Tcp | NetworkProtocol::Udp => {
+                    self.send_kdc(request.protocol, &request.url, &request.data)
+                        .await
                 }
-                sspi::network_client::NetworkProtocol::Udp => {
-                    self.send_udp(&request.url, &request.data).await
-                }
-                sspi::network_client::NetworkProtocol::Http
-                | sspi::network_client::NetworkProtocol::Https => {
+                NetworkProtocol::Http | NetworkProtocol::Https => {
                     self.send_http(&request.url, &request.data).await
                 }
             }
@@ -37,84 +54,231 @@ impl AsyncNetworkClient for ReqwestNetworkClient {
 }
 
 impl ReqwestNetworkClient {
-    pub(crate) fn new() -> Self {
-        Self { client: None }
+    pub(crate) fn new(kerberos: &super::RDPKerberos) -> Self {
+        Self {
+            client: None,
+            timeout: kerberos.timeout,
+            kdc_given: kerberos.kdc_url.is_some(),
+            srv_kdcs: None,
+            working_kdc: None,
+            proxy_ca: kerberos.proxy_ca.clone(),
+            proxy_insecure: kerberos.proxy_insecure,
+            proxy: None,
+            ssh_tunnel: None,
+        }
+    }
+
+    /// Reach the KDC through a proxy, over TCP as UDP can't be proxied.
+    pub(crate) fn with_proxy(mut self, proxy: Option<super::proxy::RDPProxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Reach the KDC through an SSH jump host, over TCP as SSH only forwards that.
+    pub(crate) fn with_ssh_tunnel(mut self, ssh_tunnel: Option<super::ssh::RDPSshTunnel>) -> Self {
+        self.ssh_tunnel = ssh_tunnel;
+        self
+    }
+
+    fn http_client(&mut self) -> ConnectorResult<Client> {
+        if let Some(client) = &self.client {
+            return Ok(client.clone());
+        }
+        let mut builder = Client::builder()
+            .timeout(self.timeout)
+            .danger_accept_invalid_certs(self.proxy_insecure);
+        if let Some(proxy) = &self.proxy {
+            let proxy = proxy.reqwest_proxy().map_err(|e| {
+                custom_err!("unusable proxy for the KDC proxy", std::io::Error::other(e))
+            })?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = &self.proxy_ca {
+            let pem = std::fs::read(path)
+                .map_err(|e| custom_err!("failed to read KDC proxy CA certificates", e))?;
+            let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+                .map_err(|e| custom_err!("invalid KDC proxy CA certificates", e))?;
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        let client = builder
+            .build()
+            .map_err(|e| custom_err!("failed to set up the KDC proxy client", e))?;
+        self.client = Some(client.clone());
+        Ok(client)
     }
 }
 
 impl ReqwestNetworkClient {
-    async fn send_tcp(&self, url: &Url, data: &[u8]) -> ConnectorResult<Vec<u8>> {
-        let addr = format!(
-            "{}:{}",
-            url.host_str().unwrap_or_default(),
-            url.port().unwrap_or(88)
-        );
+    /// Send a request to the KDC it names and, failing that, to the realm's other
+    /// KDCs found through DNS, unless the KDC was given explicitly.
+    async fn send_kdc(
+        &mut self,
+        protocol: NetworkProtocol,
+        url: &Url,
+        data: &[u8],
+    ) -> ConnectorResult<Vec<u8>> {
+        // Requests for TCP come with a length prefix, which UDP doesn't use.
+        let message = match protocol {
+            NetworkProtocol::Tcp => data
+                .get(4..)
+                .ok_or_else(|| general_err!("Kerberos message too short"))?,
+            _ => data,
+        };
 
-        let mut stream = TcpStream::connect(addr)
-            .await
-            .map_err(|e| Error::new(ErrorKind::NoAuthenticatingAuthority, format!("{:?}", e)))
-            .map_err(|e| custom_err!("failed to send KDC request over TCP", e))?;
+        let mut kdcs: Vec<Url> = self.working_kdc.iter().cloned().collect();
+        kdcs.push(url.clone());
+        if !self.kdc_given {
+            kdcs.extend(self.srv_kdcs(message).await);
+        }
+        let mut last_error = None;
+        for (i, kdc) in kdcs.iter().enumerate() {
+            if kdcs[..i].contains(kdc) {
+                continue;
+            }
+            match self.send_to_kdc(kdc, message).await {
+                Ok(reply) => {
+                    self.working_kdc = Some(kdc.clone());
+                    return Ok(reply);
+                }
+                Err(e) => {
+                    log::warn!("KDC {} failed: {}", kdc, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| general_err!("no KDC to send to")))
+    }
 
-        stream
-            .write(data)
-            .await
-            .map_err(|e| Error::new(ErrorKind::NoAuthenticatingAuthority, format!("{:?}", e)))
-            .map_err(|e| custom_err!("failed to send KDC request over TCP", e))?;
+    async fn send_to_kdc(&self, url: &Url, message: &[u8]) -> ConnectorResult<Vec<u8>> {
+        if url.scheme() != "udp" || self.proxy.is_some() || self.ssh_tunnel.is_some() {
+            return self.send_tcp(url, message).await;
+        }
+        let reply = self.send_udp(url, message).await?;
+        if !is_response_too_big(&reply[4..]) {
+            return Ok(reply);
+        }
+        log::debug!("KDC reply too big for UDP, retrying over TCP");
+        let mut url = url.clone();
+        url.set_scheme("tcp")
+            .map_err(|_| general_err!("failed to switch KDC URL to TCP"))?;
+        self.send_tcp(&url, message).await
+    }
 
-        let len = stream
-            .read_u32()
-            .await
-            .map_err(|e| Error::new(ErrorKind::NoAuthenticatingAuthority, format!("{:?}", e)))
-            .map_err(|e| custom_err!("failed to send KDC request over TCP", e))?;
+    async fn send_tcp(&self, url: &Url, message: &[u8]) -> ConnectorResult<Vec<u8>> {
+        let (host, port) = kdc_address(url)?;
+        let len =
+            u32::try_from(message.len()).map_err(|_| general_err!("Kerberos message too long"))?;
+        let exchange = async {
+            let mut stream: Box<dyn super::RDPStream> = match &self.ssh_tunnel {
+                Some(ssh_tunnel) => Box::new(
+                    ssh_tunnel
+                        .connect(&host, port)
+                        .await
+                        .map_err(std::io::Error::other)?,
+                ),
+                None => Box::new(
+                    super::proxy::connect(self.proxy.as_ref(), &host, port)
+                        .await
+                        .map_err(std::io::Error::other)?,
+                ),
+            };
+            stream
+                .write_all(&[&len.to_be_bytes(), message].concat())
+                .await?;
 
-        let mut buf = vec![0; len as usize + 4];
-        buf[0..4].copy_from_slice(&(len.to_be_bytes()));
+            let len = stream.read_u32().await?;
+            if len > MAX_KDC_REPLY_LEN {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("KDC reply of {} bytes is too long", len),
+                ));
+            }
+            let mut buf = vec![0; len as usize + 4];
+            buf[0..4].copy_from_slice(&(len.to_be_bytes()));
+            stream.read_exact(&mut buf[4..]).await?;
+            Ok(buf)
+        };
 
-        stream
-            .read_exact(&mut buf[4..])
+        tokio::time::timeout(self.timeout, exchange)
             .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
             .map_err(|e| Error::new(ErrorKind::NoAuthenticatingAuthority, format!("{:?}", e)))
-            .map_err(|e| custom_err!("failed to send KDC request over TCP", e))?;
-
-        Ok(buf)
+            .map_err(|e| custom_err!("failed to send KDC request over TCP", e))
     }
 
-    async fn send_udp(&self, url: &Url, data: &[u8]) -> ConnectorResult<Vec<u8>> {
-        let udp_socket = UdpSocket::bind((IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+    async fn send_udp(&self, url: &Url, message: &[u8]) -> ConnectorResult<Vec<u8>> {
+        let (host, port) = kdc_address(url)?;
+        let addr = tokio::net::lookup_host((host.as_str(), port))
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| general_err!("failed to resolve the KDC's address"))?;
+        let local: IpAddr = if addr.is_ipv6() {
+            Ipv6Addr::UNSPECIFIED.into()
+        } else {
+            Ipv4Addr::UNSPECIFIED.into()
+        };
+        let udp_socket = UdpSocket::bind((local, 0))
             .await
             .map_err(|e| custom_err!("cannot bind UDP socket", e))?;
-
-        let addr = format!(
-            "{}:{}",
-            url.host_str().unwrap_or_default(),
-            url.port().unwrap_or(88)
-        );
-
         udp_socket
-            .send_to(data, addr)
+            .connect(addr)
             .await
-            .map_err(|e| custom_err!("failed to send UDP request", e))?;
+            .map_err(|e| custom_err!("cannot connect UDP socket", e))?;
 
         // 48 000 bytes: default maximum token len in Windows
         let mut buf = vec![0; 0xbb80];
 
-        let n = udp_socket
-            .recv(&mut buf)
-            .await
-            .map_err(|e| custom_err!("failed to receive UDP request", e))?;
+        // Datagrams get lost, so resend a few times within the timeout.
+        for _ in 0..UDP_ATTEMPTS {
+            udp_socket
+                .send(message)
+                .await
+                .map_err(|e| custom_err!("failed to send UDP request", e))?;
+            let received =
+                tokio::time::timeout(self.timeout / UDP_ATTEMPTS, udp_socket.recv(&mut buf)).await;
+            let Ok(received) = received else {
+                continue;
+            };
+            let n = received.map_err(|e| custom_err!("failed to receive UDP request", e))?;
 
-        let mut reply_buf = Vec::with_capacity(n + 4);
-        reply_buf.extend_from_slice(&(n as u32).to_be_bytes());
-        reply_buf.extend_from_slice(&buf[0..n]);
+            let mut reply_buf = Vec::with_capacity(n + 4);
+            reply_buf.extend_from_slice(&(n as u32).to_be_bytes());
+            reply_buf.extend_from_slice(&buf[0..n]);
+            return Ok(reply_buf);
+        }
+        Err(custom_err!(
+            "failed to receive UDP request",
+            std::io::Error::from(std::io::ErrorKind::TimedOut)
+        ))
+    }
 
-        Ok(reply_buf)
+    /// The realm's KDCs from its SRV records, looked up once.
+    async fn srv_kdcs(&mut self, message: &[u8]) -> Vec<Url> {
+        if let Some(kdcs) = &self.srv_kdcs {
+            return kdcs.clone();
+        }
+        let kdcs = match request_realm(message) {
+            Some(realm) => tokio::time::timeout(self.timeout, lookup_srv_kdcs(&realm))
+                .await
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+        self.srv_kdcs = Some(kdcs.clone());
+        kdcs
     }
 
+    /// Send a request to a KDC proxy as described by MS-KKDCP. sspi has already wrapped
+    /// it in a KDC-PROXY-MESSAGE, and unwraps the reply.
     async fn send_http(&mut self, url: &Url, data: &[u8]) -> ConnectorResult<Vec<u8>> {
-        let client = self.client.get_or_insert_with(Client::new);
+        let client = self.http_client()?;
 
         let response = client
             .post(url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/kerberos")
+            .header(reqwest::header::CACHE_CONTROL, "no-cache")
             .body(data.to_vec())
             .send()
             .await
@@ -130,6 +294,406 @@ impl ReqwestNetworkClient {
         // The type bytes::Bytes has a special From implementation for Vec<u8>.
         let body = Vec::from(body);
 
+        KdcProxyMessage::from_raw(&body)
+            .map_err(|e| custom_err!("KDC proxy reply is not a KDC-PROXY-MESSAGE", e))?;
         Ok(body)
     }
 }
+
+fn kdc_address(url: &Url) -> ConnectorResult<(String, u16)> {
+    let host = match url.host() {
+        Some(url::Host::Domain(name)) => name.to_string(),
+        Some(url::Host::Ipv4(ip)) => ip.to_string(),
+        Some(url::Host::Ipv6(ip)) => ip.to_string(),
+        None => return Err(general_err!("KDC URL has no host")),
+    };
+    Ok((host, url.port().unwrap_or(88)))
+}
+
+fn is_response_too_big(reply: &[u8]) -> bool {
+    picky_asn1_der::from_bytes::<KrbError>(reply)
+        .is_ok_and(|error| error.0.error_code.0 == KRB_ERR_RESPONSE_TOO_BIG)
+}
+
+/// The realm an AS-REQ or TGS-REQ is for.
+fn request_realm(message: &[u8]) -> Option<String> {
+    let request = picky_asn1_der::from_bytes::<AsReq>(message)
+        .map(|request| request.0)
+        .or_else(|_| picky_asn1_der::from_bytes::<TgsReq>(message).map(|request| request.0))
+        .ok()?;
+    Some(request.req_body.0.realm.0.to_string())
+}
+
+async fn lookup_srv_kdcs(realm: &str) -> Vec<Url> {
+    let Ok(resolver) = hickory_resolver::TokioAsyncResolver::tokio_from_system_conf() else {
+        return Vec::new();
+    };
+    let mut records = Vec::new();
+    for protocol in ["tcp", "udp"] {
+        let name = format!("_kerberos._{}.{}.", protocol, realm.to_lowercase());
+        if let Ok(lookup) = resolver.srv_lookup(name).await {
+            records.extend(lookup.iter().map(|srv| SrvRecord {
+                priority: srv.priority(),
+                weight: srv.weight(),
+                protocol,
+                target: srv.target().to_utf8(),
+                port: srv.port(),
+            }));
+        }
+    }
+    srv_urls(records)
+}
+
+struct SrvRecord {
+    priority: u16,
+    weight: u16,
+    protocol: &'static str,
+    target: String,
+    port: u16,
+}
+
+/// KDC URLs in the order to try them: by priority, then the heaviest first.
+fn srv_urls(mut records: Vec<SrvRecord>) -> Vec<Url> {
+    records.sort_by_key(|record| (record.priority, std::cmp::Reverse(record.weight)));
+    records
+        .iter()
+        .filter_map(|record| {
+            let target = record.target.trim_end_matches('.');
+            // A target of "." means the service isn't offered.
+            if target.is_empty() {
+                return None;
+            }
+            Url::parse(&format!("{}://{}:{}", record.protocol, target, record.port)).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// A Kerberos message as sspi gives it for TCP, with its length prefix.
+    const AS_REQ: &[u8] = &[0, 0, 0, 3, 0x6a, 0x01, 0x00];
+
+    /// Serve a single HTTP request with `response`, handing back the request.
+    async fn http_stand_in(response: Vec<u8>) -> (Url, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/KdcProxy",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let stand_in = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            let body_len = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_ascii_lowercase();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let content_length = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .map_or(0, |len| len.trim().parse().unwrap());
+                    break end + 4 + content_length;
+                }
+            };
+            while request.len() < body_len {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(&response).await.unwrap();
+            request
+        });
+        (url, stand_in)
+    }
+
+    fn http_response(status: &str, body: &[u8]) -> Vec<u8> {
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/kerberos\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        );
+        [head.as_bytes(), body].concat()
+    }
+
+    fn client(timeout: Duration) -> ReqwestNetworkClient {
+        ReqwestNetworkClient::new(&crate::rdp::RDPKerberos {
+            timeout,
+            ..Default::default()
+        })
+    }
+
+    /// The request sspi makes of a KDC proxy goes out as it is, and the reply comes
+    /// back for sspi to unwrap.
+    #[tokio::test]
+    async fn proxy_request() {
+        use ironrdp::connector::credssp::{CredsspSequence, KerberosConfig};
+        use sspi::generator::GeneratorState;
+
+        let reply = KdcProxyMessage::from_raw_kerb_message(&[0, 0, 0, 1, 0x6b])
+            .unwrap()
+            .to_vec()
+            .unwrap();
+        let (url, stand_in) = http_stand_in(http_response("200 OK", &reply)).await;
+
+        let (mut sequence, ts_request) = CredsspSequence::init(
+            ironrdp::connector::Credentials::UsernamePassword {
+                username: "tester@LAB.EXAMPLE".into(),
+                password: "secret".into(),
+            },
+            None,
+            ironrdp::pdu::nego::SecurityProtocol::HYBRID,
+            "vm1.lab.example".into(),
+            vec![0; 32],
+            Some(KerberosConfig {
+                kdc_proxy_url: Some(url),
+                hostname: Some("client1".into()),
+            }),
+        )
+        .unwrap();
+        // As for a KDC, the second round asks the proxy for a ticket.
+        let mut network_client = client(Duration::from_secs(10));
+        let empty_reply = sspi::credssp::TsRequest {
+            nego_tokens: Some(Vec::new()),
+            ..Default::default()
+        };
+        let mut response = None;
+        for ts_request in [ts_request, empty_reply] {
+            let mut generator = sequence.process_ts_request(ts_request);
+            if let GeneratorState::Suspended(request) = generator.start() {
+                assert_eq!(request.protocol, NetworkProtocol::Http);
+                response = Some(network_client.send(&request).await.unwrap());
+                break;
+            }
+        }
+        assert_eq!(response.expect("No request for the KDC proxy"), reply);
+
+        let request = stand_in.await.unwrap();
+        let text = String::from_utf8_lossy(&request).to_ascii_lowercase();
+        assert!(text.starts_with("post /kdcproxy "));
+        assert!(text.contains("content-type: application/kerberos"));
+        assert!(text.contains("cache-control: no-cache"));
+        let body = &request[text.find("\r\n\r\n").unwrap() + 4..];
+        let message = KdcProxyMessage::from_raw(body).unwrap();
+        let kerb_message = &message.kerb_message.0 .0;
+        assert_eq!(
+            u32::from_be_bytes(kerb_message[..4].try_into().unwrap()) as usize,
+            kerb_message.len() - 4
+        );
+        let contains = |s: &str| body.windows(s.len()).any(|w| w == s.as_bytes());
+        assert!(contains("LAB.EXAMPLE"));
+        assert!(contains("krbtgt"));
+    }
+
+    #[tokio::test]
+    async fn proxy_request_through_socks5() {
+        let reply = KdcProxyMessage::from_raw_kerb_message(&[0, 0, 0, 1, 0x6b])
+            .unwrap()
+            .to_vec()
+            .unwrap();
+        let (url, stand_in) = http_stand_in(http_response("200 OK", &reply)).await;
+        let target = format!("{}:{}", url.host_str().unwrap(), url.port().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("socks5h://tester:secret@{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let socks = tokio::spawn(crate::rdp::proxy::tests::socks_stand_in(
+            listener,
+            Some(target.parse().unwrap()),
+        ));
+
+        let mut client = client(Duration::from_secs(10)).with_proxy(Some(proxy));
+        assert_eq!(client.send_http(&url, &reply).await.unwrap(), reply);
+        assert!(String::from_utf8_lossy(&stand_in.await.unwrap()).starts_with("POST /KdcProxy "));
+        drop(client);
+        let (_, auth, _) = socks.await.unwrap();
+        assert_eq!(auth, b"\x01\x06tester\x06secret");
+    }
+
+    #[tokio::test]
+    async fn proxy_failures() {
+        let (url, _) = http_stand_in(http_response("404 Not Found", b"")).await;
+        assert!(client(Duration::from_secs(10))
+            .send_http(&url, AS_REQ)
+            .await
+            .is_err());
+
+        let (url, _) = http_stand_in(http_response("200 OK", b"<html></html>")).await;
+        assert!(client(Duration::from_secs(10))
+            .send_http(&url, AS_REQ)
+            .await
+            .is_err());
+
+        // Accepts the connection but never replies.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/KdcProxy",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let _silent = tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+        let started = std::time::Instant::now();
+        assert!(client(Duration::from_millis(200))
+            .send_http(&url, AS_REQ)
+            .await
+            .is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    /// A KRB-ERROR with the code KRB_ERR_RESPONSE_TOO_BIG.
+    const RESPONSE_TOO_BIG: &[u8] = &[
+        126, 129, 146, 48, 129, 143, 160, 3, 2, 1, 5, 161, 3, 2, 1, 30, 164, 17, 24, 15, 50, 48,
+        50, 49, 49, 50, 51, 49, 49, 49, 48, 54, 48, 49, 90, 165, 5, 2, 3, 10, 12, 135, 166, 3, 2,
+        1, 52, 167, 13, 27, 11, 69, 88, 65, 77, 80, 76, 69, 46, 67, 79, 77, 168, 19, 48, 17, 160,
+        3, 2, 1, 1, 161, 10, 48, 8, 27, 6, 109, 121, 117, 115, 101, 114, 169, 13, 27, 11, 69, 88,
+        65, 77, 80, 76, 69, 46, 67, 79, 77, 170, 34, 48, 32, 160, 3, 2, 1, 2, 161, 25, 48, 23, 27,
+        8, 115, 111, 109, 101, 110, 97, 109, 101, 27, 11, 69, 88, 65, 77, 80, 76, 69, 46, 67, 79,
+        77, 171, 13, 27, 11, 80, 82, 79, 67, 69, 83, 83, 95, 84, 71, 83,
+    ];
+
+    /// A KDC which answers one request over TCP, writing the reply in pieces.
+    async fn tcp_kdc(listener: TcpListener, reply: &'static [u8]) -> Vec<u8> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let len = stream.read_u32().await.unwrap();
+        let mut request = vec![0; len as usize];
+        stream.read_exact(&mut request).await.unwrap();
+        stream
+            .write_all(&(reply.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        for piece in reply.chunks(2) {
+            stream.write_all(piece).await.unwrap();
+            stream.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn tcp_reply_read_fully() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("tcp://{}", listener.local_addr().unwrap())).unwrap();
+        let kdc = tokio::spawn(tcp_kdc(listener, &[0x6b, 1, 2, 3, 4]));
+
+        let reply = client(Duration::from_secs(10))
+            .send_kdc(NetworkProtocol::Tcp, &url, AS_REQ)
+            .await
+            .unwrap();
+        assert_eq!(reply, [0, 0, 0, 5, 0x6b, 1, 2, 3, 4]);
+        assert_eq!(kdc.await.unwrap(), &AS_REQ[4..]);
+    }
+
+    #[tokio::test]
+    async fn udp_falls_back_to_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let udp = UdpSocket::bind(address).await.unwrap();
+        let tcp = tokio::spawn(tcp_kdc(listener, &[0x6b, 1]));
+        let udp = tokio::spawn(async move {
+            let mut buf = [0; 100];
+            // Ignore the first datagram, as if it were lost.
+            udp.recv_from(&mut buf).await.unwrap();
+            let (n, peer) = udp.recv_from(&mut buf).await.unwrap();
+            udp.send_to(RESPONSE_TOO_BIG, peer).await.unwrap();
+            buf[..n].to_vec()
+        });
+
+        let url = Url::parse(&format!("udp://{}", address)).unwrap();
+        let reply = client(Duration::from_secs(3))
+            .send_kdc(NetworkProtocol::Udp, &url, &AS_REQ[4..])
+            .await
+            .unwrap();
+        assert_eq!(reply, [0, 0, 0, 2, 0x6b, 1]);
+        assert_eq!(udp.await.unwrap(), &AS_REQ[4..]);
+        assert_eq!(tcp.await.unwrap(), &AS_REQ[4..]);
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_next_kdc() {
+        // Nothing listens on a port just released.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = Url::parse(&format!("tcp://{}", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = Url::parse(&format!("tcp://{}", listener.local_addr().unwrap())).unwrap();
+        let _kdc = tokio::spawn(tcp_kdc(listener, &[0x6b]));
+
+        let mut client = client(Duration::from_secs(10));
+        client.srv_kdcs = Some(vec![dead.clone(), live.clone()]);
+        client
+            .send_kdc(NetworkProtocol::Tcp, &dead, AS_REQ)
+            .await
+            .unwrap();
+        assert_eq!(client.working_kdc, Some(live.clone()));
+
+        // A KDC given by the user is the only one tried.
+        let mut client = ReqwestNetworkClient::new(&crate::rdp::RDPKerberos {
+            kdc_url: Some(dead.clone()),
+            ..Default::default()
+        });
+        client.srv_kdcs = Some(vec![live]);
+        assert!(client
+            .send_kdc(NetworkProtocol::Tcp, &dead, AS_REQ)
+            .await
+            .is_err());
+    }
+
+    /// Even a UDP KDC is asked over TCP through the jump host, here something which
+    /// only takes the connection.
+    #[tokio::test]
+    async fn kdc_through_ssh_tunnel() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tunnel = format!("tester@{}", listener.local_addr().unwrap())
+            .parse::<crate::rdp::ssh::RDPSshTunnel>()
+            .unwrap();
+        let jump_host = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"not SSH\r\n").await.unwrap();
+        });
+        let mut client = ReqwestNetworkClient::new(&crate::rdp::RDPKerberos {
+            kdc_url: Some(Url::parse("udp://127.0.0.1").unwrap()),
+            timeout: Duration::from_secs(10),
+            ..Default::default()
+        })
+        .with_ssh_tunnel(Some(tunnel));
+        let url = Url::parse("udp://127.0.0.1:88").unwrap();
+        let error = client
+            .send_kdc(NetworkProtocol::Udp, &url, &AS_REQ[4..])
+            .await
+            .unwrap_err();
+        assert!(format!("{:?}", error).contains("SSH jump host"));
+        jump_host.await.unwrap();
+    }
+
+    #[test]
+    fn srv_records_ordered() {
+        let record = |priority, weight, protocol, target: &str| SrvRecord {
+            priority,
+            weight,
+            protocol,
+            target: target.to_string(),
+            port: 88,
+        };
+        let urls = srv_urls(vec![
+            record(10, 0, "udp", "kdc3.lab.example."),
+            record(0, 10, "tcp", "kdc2.lab.example."),
+            record(0, 50, "tcp", "kdc1.lab.example."),
+            record(0, 0, "tcp", "."),
+        ]);
+        let urls: Vec<String> = urls.iter().map(Url::to_string).collect();
+        assert_eq!(
+            urls,
+            [
+                "tcp://kdc1.lab.example:88",
+                "tcp://kdc2.lab.example:88",
+                "udp://kdc3.lab.example:88"
+            ]
+        );
+    }
+}
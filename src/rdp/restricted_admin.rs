@@ -0,0 +1,255 @@
+//! Restricted Admin mode, in which NLA authenticates the user but doesn't hand the
+//! server their credentials. IronRDP's connector always delegates them, so this does
+//! the connection request and CredSSP itself.
+
+use ironrdp::connector::{
+    custom_err, general_err, reason_err, ClientConnector, ConnectorError, ConnectorErrorExt as _,
+    ConnectorErrorKind, ConnectorResult, Credentials, Sequence as _,
+};
+use ironrdp::pdu::{nego, x224::X224, PduHint};
+use ironrdp_core::{decode, encode_vec, WriteBuf};
+use ironrdp_tokio::{AsyncNetworkClient, Framed, FramedRead, FramedWrite, ShouldUpgrade};
+use sspi::credssp::{
+    ClientMode, ClientState, CredSspClient, CredSspMode, EarlyUserAuthResult, TsRequest,
+    EARLY_USER_AUTH_RESULT_PDU_SIZE,
+};
+use sspi::generator::GeneratorState;
+
+/// Ask for Restricted Admin mode in the connection request, failing if the server
+/// doesn't support it.
+pub(crate) async fn connect_begin<S>(
+    framed: &mut Framed<S>,
+    connector: &mut ClientConnector,
+) -> ConnectorResult<ShouldUpgrade>
+where
+    S: FramedRead + FramedWrite,
+{
+    let mut buf = WriteBuf::new();
+    connector.step_no_input(&mut buf)?;
+    let mut request = decode::<X224<nego::ConnectionRequest>>(buf.filled())
+        .map_err(ConnectorError::decode)?
+        .0;
+    request.flags |= nego::RequestFlags::RESTRICTED_ADMIN_MODE_REQUIRED;
+    let request = encode_vec(&X224(request)).map_err(ConnectorError::encode)?;
+    framed
+        .write_all(&request)
+        .await
+        .map_err(|e| custom_err!("write all", e))?;
+
+    let hint = connector
+        .next_pdu_hint()
+        .ok_or_else(|| general_err!("no connection confirm expected"))?;
+    let pdu = framed
+        .read_by_hint(hint)
+        .await
+        .map_err(|e| custom_err!("read frame by hint", e))?;
+    if let nego::ConnectionConfirm::Response { flags, .. } =
+        decode::<X224<nego::ConnectionConfirm>>(&pdu)
+            .map_err(ConnectorError::decode)?
+            .0
+    {
+        if !flags.contains(nego::ResponseFlags::RESTRICTED_ADMIN_MODE_SUPPORTED) {
+            return Err(reason_err!(
+                "Initiation",
+                "the server doesn't support Restricted Admin mode"
+            ));
+        }
+    }
+    // Failures are reported by the connector as usual.
+    buf.clear();
+    connector.step(&pdu, &mut buf)?;
+    Ok(ironrdp_tokio::skip_connect_begin(connector))
+}
+
+/// Authenticate with CredSSP without sending the credentials, then leave them out of
+/// the client info too.
+pub(crate) async fn perform_credssp<S>(
+    framed: &mut Framed<S>,
+    connector: &mut ClientConnector,
+    server_name: &str,
+    server_public_key: Vec<u8>,
+    network_client: &mut dyn AsyncNetworkClient,
+    kerberos_config: Option<ironrdp::connector::credssp::KerberosConfig>,
+) -> ConnectorResult<()>
+where
+    S: FramedRead + FramedWrite,
+{
+    let Some(selected_protocol) = credssp_protocol(connector) else {
+        return Err(reason_err!("Initiation", "Restricted Admin mode needs NLA"));
+    };
+    let Credentials::UsernamePassword { username, password } = &mut connector.config.credentials
+    else {
+        return Err(general_err!(
+            "Restricted Admin mode is only supported with a password"
+        ));
+    };
+    let identity = sspi::AuthIdentity {
+        username: sspi::Username::new(username, connector.config.domain.as_deref())
+            .map_err(|e| custom_err!("invalid username", e))?,
+        password: std::mem::take(password).into(),
+    };
+
+    let protocol_config: Box<dyn sspi::negotiate::ProtocolConfig> = match kerberos_config {
+        Some(kerberos_config) => Box::new(sspi::KerberosConfig::from(kerberos_config)),
+        None => Box::<sspi::ntlm::NtlmConfig>::default(),
+    };
+    let mut client = CredSspClient::new(
+        server_public_key,
+        identity.into(),
+        CredSspMode::CredentialLess,
+        ClientMode::Negotiate(sspi::NegotiateConfig {
+            protocol_config,
+            package_list: None,
+            client_computer_name: server_name.to_string(),
+        }),
+        format!("TERMSRV/{}", server_name),
+    )
+    .map_err(credssp_err)?;
+
+    let mut request = TsRequest::default();
+    loop {
+        let state = {
+            let mut generator = client.process(request);
+            let mut state = generator.start();
+            loop {
+                match state {
+                    GeneratorState::Suspended(request) => {
+                        let response = network_client.send(&request).await?;
+                        state = generator.resume(Ok(response));
+                    }
+                    GeneratorState::Completed(result) => break result.map_err(credssp_err)?,
+                }
+            }
+        };
+        let (reply, done) = match state {
+            ClientState::ReplyNeeded(reply) => (reply, false),
+            ClientState::FinalMessage(reply) => (reply, true),
+        };
+        let mut buf = vec![0; usize::from(reply.buffer_len())];
+        reply
+            .encode_ts_request(&mut buf)
+            .map_err(|e| custom_err!("TsRequest", e))?;
+        framed
+            .write_all(&buf)
+            .await
+            .map_err(|e| custom_err!("write all", e))?;
+        if done {
+            break;
+        }
+
+        let pdu = framed
+            .read_by_hint(&TsRequestHint)
+            .await
+            .map_err(|e| custom_err!("read frame by hint", e))?;
+        request = TsRequest::from_buffer(&pdu).map_err(|e| custom_err!("TsRequest", e))?;
+    }
+
+    if selected_protocol.contains(nego::SecurityProtocol::HYBRID_EX) {
+        let pdu = framed
+            .read_by_hint(&EarlyUserAuthResultHint)
+            .await
+            .map_err(|e| custom_err!("read frame by hint", e))?;
+        match EarlyUserAuthResult::from_buffer(&pdu[..])
+            .map_err(|e| custom_err!("EarlyUserAuthResult", e))?
+        {
+            EarlyUserAuthResult::Success => {}
+            EarlyUserAuthResult::AccessDenied => {
+                return Err(ConnectorError::new(
+                    "CredSSP",
+                    ConnectorErrorKind::AccessDenied,
+                ))
+            }
+        }
+    }
+    connector.mark_credssp_as_done();
+    Ok(())
+}
+
+fn credssp_protocol(connector: &ClientConnector) -> Option<nego::SecurityProtocol> {
+    match connector.state {
+        ironrdp::connector::ClientConnectorState::Credssp { selected_protocol } => {
+            Some(selected_protocol)
+        }
+        _ => None,
+    }
+}
+
+fn credssp_err(e: sspi::Error) -> ConnectorError {
+    ConnectorError::new("CredSSP", ConnectorErrorKind::Credssp(e))
+}
+
+#[derive(Debug)]
+struct TsRequestHint;
+
+impl PduHint for TsRequestHint {
+    fn find_size(&self, bytes: &[u8]) -> ironrdp_core::DecodeResult<Option<(bool, usize)>> {
+        match TsRequest::read_length(bytes) {
+            Ok(length) => Ok(Some((true, length))),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(ironrdp_core::other_err!("TsRequestHint", source: e)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct EarlyUserAuthResultHint;
+
+impl PduHint for EarlyUserAuthResultHint {
+    fn find_size(&self, _: &[u8]) -> ironrdp_core::DecodeResult<Option<(bool, usize)>> {
+        Ok(Some((true, EARLY_USER_AUTH_RESULT_PDU_SIZE)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Negotiate with a server which answers with the given flags, returning whether
+    /// that succeeded and the request it was sent.
+    async fn negotiate(flags: nego::ResponseFlags) -> (bool, nego::ConnectionRequest) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            let len = u16::from_be_bytes([request[2], request[3]]) as usize;
+            request.resize(len, 0);
+            stream.read_exact(&mut request[4..]).await.unwrap();
+            let confirm = nego::ConnectionConfirm::Response {
+                flags,
+                protocol: nego::SecurityProtocol::HYBRID,
+            };
+            stream
+                .write_all(&encode_vec(&X224(confirm)).unwrap())
+                .await
+                .unwrap();
+            decode::<X224<nego::ConnectionRequest>>(&request).unwrap().0
+        });
+
+        let credentials = crate::rdp::RDPCredentials::new("tester".into(), "secret".into(), None);
+        let session = crate::rdp::RDPSession::from_credentials(credentials);
+        let mut connector = ClientConnector::new(session.config);
+        let mut framed =
+            ironrdp_tokio::TokioFramed::new(TcpStream::connect(address).await.unwrap());
+        let result = connect_begin(&mut framed, &mut connector).await;
+        if result.is_ok() {
+            assert!(connector.should_perform_security_upgrade());
+        }
+        (result.is_ok(), server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn restricted_admin_requested() {
+        let (ok, request) = negotiate(nego::ResponseFlags::RESTRICTED_ADMIN_MODE_SUPPORTED).await;
+        assert!(ok);
+        assert!(request
+            .flags
+            .contains(nego::RequestFlags::RESTRICTED_ADMIN_MODE_REQUIRED));
+
+        let (ok, _) = negotiate(nego::ResponseFlags::empty()).await;
+        assert!(!ok);
+    }
+}
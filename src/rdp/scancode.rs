@@ -0,0 +1,391 @@
+use ironrdp::pdu::input::fast_path::{FastPathInputEvent, KeyboardFlags};
+
+/// Prefix byte of a Set-1 scan code, which RDP carries as keyboard event flags.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ScancodePrefix {
+    None,
+    /// 0xE0, sent as KBDFLAGS_EXTENDED.
+    E0,
+    /// 0xE1, sent as KBDFLAGS_EXTENDED1. Only used by Pause.
+    E1,
+}
+
+/// A Set-1 ("XT") make code.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Scancode {
+    pub prefix: ScancodePrefix,
+    pub code: u8,
+}
+
+impl Scancode {
+    pub const fn new(code: u8) -> Self {
+        Self {
+            prefix: ScancodePrefix::None,
+            code,
+        }
+    }
+
+    pub const fn extended(code: u8) -> Self {
+        Self {
+            prefix: ScancodePrefix::E0,
+            code,
+        }
+    }
+
+    /// Fast-path keyboard events for pressing or releasing this key.
+    ///
+    /// Pause is sent as the E1-prefixed Ctrl code followed by the Num Lock code
+    /// (E1 1D 45), as Windows keyboards do. A real keyboard sends no break codes
+    /// for it, but the server expects the same pair flagged as released.
+    pub fn events(self, pressed: bool) -> Vec<FastPathInputEvent> {
+        let flags = if pressed {
+            KeyboardFlags::empty()
+        } else {
+            KeyboardFlags::RELEASE
+        };
+        match self.prefix {
+            ScancodePrefix::None => vec![FastPathInputEvent::KeyboardEvent(flags, self.code)],
+            ScancodePrefix::E0 => vec![FastPathInputEvent::KeyboardEvent(
+                flags | KeyboardFlags::EXTENDED,
+                self.code,
+            )],
+            ScancodePrefix::E1 => vec![
+                FastPathInputEvent::KeyboardEvent(flags | KeyboardFlags::EXTENDED1, self.code),
+                FastPathInputEvent::KeyboardEvent(flags, 0x45),
+            ],
+        }
+    }
+}
+
+macro_rules! physical_keys {
+    ($($key:ident => $scancode:expr,)*) => {
+        /// Physical key positions on a PC keyboard, named after the W3C UI Events
+        /// `KeyboardEvent.code` values so that they are independent of the layout.
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+        pub enum PhysicalKey {
+            $($key,)*
+        }
+
+        impl PhysicalKey {
+            pub const ALL: &'static [PhysicalKey] = &[$(PhysicalKey::$key,)*];
+
+            pub fn scancode(self) -> Scancode {
+                match self {
+                    $(PhysicalKey::$key => $scancode,)*
+                }
+            }
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(PhysicalKey::$key => stringify!($key),)*
+                }
+            }
+        }
+    };
+}
+
+// Per the Microsoft keyboard scan code specification. F25 to F35 have no Set-1
+// code at all, so they cannot be represented.
+physical_keys! {
+    Escape => Scancode::new(0x01),
+    Digit1 => Scancode::new(0x02),
+    Digit2 => Scancode::new(0x03),
+    Digit3 => Scancode::new(0x04),
+    Digit4 => Scancode::new(0x05),
+    Digit5 => Scancode::new(0x06),
+    Digit6 => Scancode::new(0x07),
+    Digit7 => Scancode::new(0x08),
+    Digit8 => Scancode::new(0x09),
+    Digit9 => Scancode::new(0x0a),
+    Digit0 => Scancode::new(0x0b),
+    Minus => Scancode::new(0x0c),
+    Equal => Scancode::new(0x0d),
+    Backspace => Scancode::new(0x0e),
+    Tab => Scancode::new(0x0f),
+    KeyQ => Scancode::new(0x10),
+    KeyW => Scancode::new(0x11),
+    KeyE => Scancode::new(0x12),
+    KeyR => Scancode::new(0x13),
+    KeyT => Scancode::new(0x14),
+    KeyY => Scancode::new(0x15),
+    KeyU => Scancode::new(0x16),
+    KeyI => Scancode::new(0x17),
+    KeyO => Scancode::new(0x18),
+    KeyP => Scancode::new(0x19),
+    BracketLeft => Scancode::new(0x1a),
+    BracketRight => Scancode::new(0x1b),
+    Enter => Scancode::new(0x1c),
+    ControlLeft => Scancode::new(0x1d),
+    KeyA => Scancode::new(0x1e),
+    KeyS => Scancode::new(0x1f),
+    KeyD => Scancode::new(0x20),
+    KeyF => Scancode::new(0x21),
+    KeyG => Scancode::new(0x22),
+    KeyH => Scancode::new(0x23),
+    KeyJ => Scancode::new(0x24),
+    KeyK => Scancode::new(0x25),
+    KeyL => Scancode::new(0x26),
+    Semicolon => Scancode::new(0x27),
+    Quote => Scancode::new(0x28),
+    Backquote => Scancode::new(0x29),
+    ShiftLeft => Scancode::new(0x2a),
+    Backslash => Scancode::new(0x2b),
+    KeyZ => Scancode::new(0x2c),
+    KeyX => Scancode::new(0x2d),
+    KeyC => Scancode::new(0x2e),
+    KeyV => Scancode::new(0x2f),
+    KeyB => Scancode::new(0x30),
+    KeyN => Scancode::new(0x31),
+    KeyM => Scancode::new(0x32),
+    Comma => Scancode::new(0x33),
+    Period => Scancode::new(0x34),
+    Slash => Scancode::new(0x35),
+    ShiftRight => Scancode::new(0x36),
+    NumpadMultiply => Scancode::new(0x37),
+    AltLeft => Scancode::new(0x38),
+    Space => Scancode::new(0x39),
+    CapsLock => Scancode::new(0x3a),
+    F1 => Scancode::new(0x3b),
+    F2 => Scancode::new(0x3c),
+    F3 => Scancode::new(0x3d),
+    F4 => Scancode::new(0x3e),
+    F5 => Scancode::new(0x3f),
+    F6 => Scancode::new(0x40),
+    F7 => Scancode::new(0x41),
+    F8 => Scancode::new(0x42),
+    F9 => Scancode::new(0x43),
+    F10 => Scancode::new(0x44),
+    NumLock => Scancode::new(0x45),
+    ScrollLock => Scancode::new(0x46),
+    Numpad7 => Scancode::new(0x47),
+    Numpad8 => Scancode::new(0x48),
+    Numpad9 => Scancode::new(0x49),
+    NumpadSubtract => Scancode::new(0x4a),
+    Numpad4 => Scancode::new(0x4b),
+    Numpad5 => Scancode::new(0x4c),
+    Numpad6 => Scancode::new(0x4d),
+    NumpadAdd => Scancode::new(0x4e),
+    Numpad1 => Scancode::new(0x4f),
+    Numpad2 => Scancode::new(0x50),
+    Numpad3 => Scancode::new(0x51),
+    Numpad0 => Scancode::new(0x52),
+    NumpadDecimal => Scancode::new(0x53),
+    IntlBackslash => Scancode::new(0x56),
+    F11 => Scancode::new(0x57),
+    F12 => Scancode::new(0x58),
+    NumpadEqual => Scancode::new(0x59),
+    F13 => Scancode::new(0x64),
+    F14 => Scancode::new(0x65),
+    F15 => Scancode::new(0x66),
+    F16 => Scancode::new(0x67),
+    F17 => Scancode::new(0x68),
+    F18 => Scancode::new(0x69),
+    F19 => Scancode::new(0x6a),
+    F20 => Scancode::new(0x6b),
+    F21 => Scancode::new(0x6c),
+    F22 => Scancode::new(0x6d),
+    F23 => Scancode::new(0x6e),
+    KanaMode => Scancode::new(0x70),
+    Lang2 => Scancode::new(0x71),
+    Lang1 => Scancode::new(0x72),
+    IntlRo => Scancode::new(0x73),
+    F24 => Scancode::new(0x76),
+    Convert => Scancode::new(0x79),
+    NonConvert => Scancode::new(0x7b),
+    IntlYen => Scancode::new(0x7d),
+    NumpadComma => Scancode::new(0x7e),
+    Undo => Scancode::extended(0x08),
+    Paste => Scancode::extended(0x0a),
+    MediaTrackPrevious => Scancode::extended(0x10),
+    Cut => Scancode::extended(0x17),
+    Copy => Scancode::extended(0x18),
+    MediaTrackNext => Scancode::extended(0x19),
+    NumpadEnter => Scancode::extended(0x1c),
+    ControlRight => Scancode::extended(0x1d),
+    AudioVolumeMute => Scancode::extended(0x20),
+    LaunchApp2 => Scancode::extended(0x21),
+    MediaPlayPause => Scancode::extended(0x22),
+    MediaStop => Scancode::extended(0x24),
+    AudioVolumeDown => Scancode::extended(0x2e),
+    AudioVolumeUp => Scancode::extended(0x30),
+    BrowserHome => Scancode::extended(0x32),
+    NumpadDivide => Scancode::extended(0x35),
+    PrintScreen => Scancode::extended(0x37),
+    AltRight => Scancode::extended(0x38),
+    Home => Scancode::extended(0x47),
+    ArrowUp => Scancode::extended(0x48),
+    PageUp => Scancode::extended(0x49),
+    ArrowLeft => Scancode::extended(0x4b),
+    ArrowRight => Scancode::extended(0x4d),
+    End => Scancode::extended(0x4f),
+    ArrowDown => Scancode::extended(0x50),
+    PageDown => Scancode::extended(0x51),
+    Insert => Scancode::extended(0x52),
+    Delete => Scancode::extended(0x53),
+    MetaLeft => Scancode::extended(0x5b),
+    MetaRight => Scancode::extended(0x5c),
+    ContextMenu => Scancode::extended(0x5d),
+    Power => Scancode::extended(0x5e),
+    Sleep => Scancode::extended(0x5f),
+    WakeUp => Scancode::extended(0x63),
+    BrowserSearch => Scancode::extended(0x65),
+    BrowserFavorites => Scancode::extended(0x66),
+    BrowserRefresh => Scancode::extended(0x67),
+    BrowserStop => Scancode::extended(0x68),
+    BrowserForward => Scancode::extended(0x69),
+    BrowserBack => Scancode::extended(0x6a),
+    LaunchApp1 => Scancode::extended(0x6b),
+    LaunchMail => Scancode::extended(0x6c),
+    MediaSelect => Scancode::extended(0x6d),
+    Pause => Scancode { prefix: ScancodePrefix::E1, code: 0x1d },
+}
+
+impl std::str::FromStr for PhysicalKey {
+    type Err = anyhow::Error;
+
+    /// Parse a key code name such as `NumpadEnter`, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PhysicalKey::ALL
+            .iter()
+            .find(|key| key.name().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Unknown key '{}'", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(flags: KeyboardFlags, code: u8) -> FastPathInputEvent {
+        FastPathInputEvent::KeyboardEvent(flags, code)
+    }
+
+    #[test]
+    fn scancode_events() {
+        let cases = [
+            (
+                PhysicalKey::KeyA,
+                true,
+                vec![key(KeyboardFlags::empty(), 0x1e)],
+            ),
+            (
+                PhysicalKey::KeyA,
+                false,
+                vec![key(KeyboardFlags::RELEASE, 0x1e)],
+            ),
+            (
+                PhysicalKey::Numpad5,
+                true,
+                vec![key(KeyboardFlags::empty(), 0x4c)],
+            ),
+            (
+                PhysicalKey::NumpadEnter,
+                true,
+                vec![key(KeyboardFlags::EXTENDED, 0x1c)],
+            ),
+            (
+                PhysicalKey::NumpadDivide,
+                true,
+                vec![key(KeyboardFlags::EXTENDED, 0x35)],
+            ),
+            (
+                PhysicalKey::Delete,
+                true,
+                vec![key(KeyboardFlags::EXTENDED, 0x53)],
+            ),
+            (
+                PhysicalKey::ControlRight,
+                false,
+                vec![key(KeyboardFlags::EXTENDED | KeyboardFlags::RELEASE, 0x1d)],
+            ),
+            (
+                PhysicalKey::PrintScreen,
+                true,
+                vec![key(KeyboardFlags::EXTENDED, 0x37)],
+            ),
+            (
+                PhysicalKey::NumLock,
+                true,
+                vec![key(KeyboardFlags::empty(), 0x45)],
+            ),
+            (
+                PhysicalKey::ScrollLock,
+                true,
+                vec![key(KeyboardFlags::empty(), 0x46)],
+            ),
+            (
+                PhysicalKey::CapsLock,
+                true,
+                vec![key(KeyboardFlags::empty(), 0x3a)],
+            ),
+            (
+                PhysicalKey::ContextMenu,
+                true,
+                vec![key(KeyboardFlags::EXTENDED, 0x5d)],
+            ),
+            (
+                PhysicalKey::AudioVolumeUp,
+                true,
+                vec![key(KeyboardFlags::EXTENDED, 0x30)],
+            ),
+            (
+                PhysicalKey::BrowserBack,
+                true,
+                vec![key(KeyboardFlags::EXTENDED, 0x6a)],
+            ),
+            (
+                PhysicalKey::F24,
+                true,
+                vec![key(KeyboardFlags::empty(), 0x76)],
+            ),
+            (
+                PhysicalKey::Pause,
+                true,
+                vec![
+                    key(KeyboardFlags::EXTENDED1, 0x1d),
+                    key(KeyboardFlags::empty(), 0x45),
+                ],
+            ),
+            (
+                PhysicalKey::Pause,
+                false,
+                vec![
+                    key(KeyboardFlags::EXTENDED1 | KeyboardFlags::RELEASE, 0x1d),
+                    key(KeyboardFlags::RELEASE, 0x45),
+                ],
+            ),
+        ];
+
+        for (physical_key, pressed, expected) in cases {
+            assert_eq!(
+                physical_key.scancode().events(pressed),
+                expected,
+                "{:?} pressed={}",
+                physical_key,
+                pressed
+            );
+        }
+    }
+
+    #[test]
+    fn scancodes_are_unique() {
+        for (i, a) in PhysicalKey::ALL.iter().enumerate() {
+            for b in &PhysicalKey::ALL[i + 1..] {
+                assert_ne!(a.scancode(), b.scancode(), "{:?} and {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for key in PhysicalKey::ALL {
+            assert_eq!(key.name().parse::<PhysicalKey>().unwrap(), *key);
+        }
+        assert_eq!(
+            "numpadenter".parse::<PhysicalKey>().unwrap(),
+            PhysicalKey::NumpadEnter
+        );
+        assert!("F35".parse::<PhysicalKey>().is_err());
+    }
+}
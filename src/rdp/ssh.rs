@@ -0,0 +1,339 @@
+//! Tunnelling through an SSH jump host. Each connection logs in to the jump host
+//! and opens a direct-tcpip channel to the target, as `ssh -W` and `ProxyJump` do,
+//! which is then forwarded to an in-memory stream.
+
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use ssh2::{BlockDirections, Channel, CheckResult, KnownHostFileKind, Session};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, Interest};
+use tokio::net::TcpStream;
+
+use super::address::RDPServerAddress;
+
+const SSH_PORT: u16 = 22;
+/// How long to wait for the jump host while logging in.
+const LOGIN_TIMEOUT_MS: u32 = 30_000;
+const BUFFER_SIZE: usize = 32 * 1024;
+
+/// An SSH jump host, written as `[USER@]HOST[:PORT]`, to log in to with a key.
+#[derive(Clone, Debug)]
+pub struct RDPSshTunnel {
+    username: Option<String>,
+    address: RDPServerAddress,
+    identity: Option<PathBuf>,
+}
+
+impl std::str::FromStr for RDPSshTunnel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (username, address) = match s.rsplit_once('@') {
+            Some((username, address)) if !username.is_empty() => {
+                (Some(username.to_string()), address)
+            }
+            Some(_) => return Err(anyhow!("Expected [USER@]HOST[:PORT], got '{}'", s)),
+            None => (None, s),
+        };
+        Ok(Self {
+            username,
+            address: address.parse()?,
+            identity: None,
+        })
+    }
+}
+
+impl std::fmt::Display for RDPSshTunnel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.username {
+            Some(username) => write!(f, "{}@{}", username, self.address),
+            None => write!(f, "{}", self.address),
+        }
+    }
+}
+
+impl RDPSshTunnel {
+    /// Log in with this private key rather than the agent's or the usual key files.
+    pub fn with_identity(mut self, identity: Option<PathBuf>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    fn port(&self) -> u16 {
+        self.address.port.unwrap_or(SSH_PORT)
+    }
+
+    /// Open a channel to `host` through the jump host, returning a stream carrying it.
+    /// The connection is closed when the stream is dropped.
+    pub(crate) async fn connect(&self, host: &str, port: u16) -> anyhow::Result<DuplexStream> {
+        log::info!(
+            "Connecting to {}:{} through SSH jump host {}",
+            host,
+            port,
+            self
+        );
+        let tunnel = self.clone();
+        let target = host.to_string();
+        let (session, channel, socket) =
+            tokio::task::spawn_blocking(move || tunnel.open_channel(&target, port))
+                .await?
+                .map_err(|e| anyhow!("SSH jump host {}: {}", self, e))?;
+
+        // From here on libssh2 is driven without blocking, waiting on the socket
+        // through a second handle to it.
+        session.set_blocking(false);
+        socket.set_nonblocking(true)?;
+        let socket = TcpStream::from_std(socket)?;
+        let (stream, local) = tokio::io::duplex(BUFFER_SIZE);
+        let destination = self.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = forward(&session, channel, &socket, local).await {
+                log::warn!("SSH tunnel through {} failed: {}", destination, e);
+            }
+            let _ = session.disconnect(None, "", None);
+        });
+        Ok(stream)
+    }
+
+    /// Log in to the jump host and open a channel to the target, blocking as we go.
+    fn open_channel(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> anyhow::Result<(Session, Channel, std::net::TcpStream)> {
+        let username = match &self.username {
+            Some(username) => username.clone(),
+            None => std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .map_err(|_| anyhow!("no username given or found in the environment"))?,
+        };
+        let socket =
+            std::net::TcpStream::connect((self.address.host.name().as_str(), self.port()))?;
+        let mut session = Session::new()?;
+        session.set_timeout(LOGIN_TIMEOUT_MS);
+        session.set_tcp_stream(socket.try_clone()?);
+        session.handshake()?;
+
+        let (host_key, _) = session
+            .host_key()
+            .ok_or_else(|| anyhow!("no host key offered"))?;
+        let known_hosts = home_dir()
+            .map(|home| home.join(".ssh").join("known_hosts"))
+            .ok_or_else(|| anyhow!("no home directory to find known_hosts in"))?;
+        let known_hosts_text = std::fs::read_to_string(&known_hosts).unwrap_or_default();
+        check_known_host(
+            &session,
+            &known_hosts_text,
+            &self.address.host.name(),
+            self.port(),
+            host_key,
+        )
+        .map_err(|e| anyhow!("{} ({})", e, known_hosts.display()))?;
+
+        self.authenticate(&session, &username)?;
+        let channel = session.channel_direct_tcpip(host, port, None)?;
+        Ok((session, channel, socket))
+    }
+
+    /// Log in with the key given, or as ssh would with the agent's keys and then the
+    /// usual key files. There's no terminal to ask for a password or passphrase on.
+    fn authenticate(&self, session: &Session, username: &str) -> anyhow::Result<()> {
+        let keys = match &self.identity {
+            Some(identity) => vec![identity.clone()],
+            None => {
+                match session.userauth_agent(username) {
+                    Ok(()) if session.authenticated() => return Ok(()),
+                    Ok(()) => {}
+                    Err(e) => log::debug!("SSH agent login failed: {}", e),
+                }
+                let ssh_dir = home_dir().unwrap_or_default().join(".ssh");
+                ["id_ed25519", "id_ecdsa", "id_rsa"]
+                    .iter()
+                    .map(|name| ssh_dir.join(name))
+                    .filter(|key| key.exists())
+                    .collect()
+            }
+        };
+        for key in keys {
+            match session.userauth_pubkey_file(username, None, &key, None) {
+                Ok(()) if session.authenticated() => return Ok(()),
+                Ok(()) => {}
+                Err(e) => log::debug!("SSH login with {} failed: {}", key.display(), e),
+            }
+        }
+        Err(anyhow!("no key was accepted for {}", username))
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+/// Check the host key against an OpenSSH known_hosts file's contents. Lines libssh2
+/// doesn't understand, such as for key types it lacks, are skipped.
+fn check_known_host(
+    session: &Session,
+    known_hosts_text: &str,
+    host: &str,
+    port: u16,
+    host_key: &[u8],
+) -> anyhow::Result<()> {
+    let mut known_hosts = session.known_hosts()?;
+    for line in known_hosts_text.lines() {
+        if let Err(e) = known_hosts.read_str(line, KnownHostFileKind::OpenSSH) {
+            log::debug!("Skipping known_hosts line: {}", e);
+        }
+    }
+    match known_hosts.check_port(host, port, host_key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(anyhow!("the host key doesn't match the known one")),
+        CheckResult::NotFound => Err(anyhow!(
+            "the host isn't known; connect to it with ssh once to add it"
+        )),
+        CheckResult::Failure => Err(anyhow!("failed to check the host key")),
+    }
+}
+
+/// Pass data between the channel and `local` until either side closes. libssh2 is
+/// non-blocking here, so its operations are retried whenever the socket is ready in
+/// the direction it was waiting for.
+async fn forward(
+    session: &Session,
+    mut channel: Channel,
+    socket: &TcpStream,
+    mut local: DuplexStream,
+) -> io::Result<()> {
+    let mut inbound = vec![0; BUFFER_SIZE];
+    let mut outbound = vec![0; BUFFER_SIZE];
+    let (mut start, mut end) = (0, 0);
+    loop {
+        // Forget what readiness has been seen, as libssh2 is about to use it up, so
+        // only what happens from here on ends the wait below.
+        let _ = socket.try_io(Interest::READABLE | Interest::WRITABLE, || {
+            Err::<(), _>(io::ErrorKind::WouldBlock.into())
+        });
+        let sent = start < end;
+        while start < end {
+            match channel.write(&outbound[start..end]) {
+                Ok(n) => start += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        match channel.read(&mut inbound) {
+            // The target closed the connection.
+            Ok(0) if channel.eof() => return local.shutdown().await,
+            Ok(0) => {}
+            Ok(n) => {
+                local.write_all(&inbound[..n]).await?;
+                continue;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        if sent && start == end {
+            continue;
+        }
+
+        let interest = match session.block_directions() {
+            BlockDirections::Outbound => Interest::WRITABLE,
+            BlockDirections::Both => Interest::READABLE | Interest::WRITABLE,
+            BlockDirections::Inbound | BlockDirections::None => Interest::READABLE,
+        };
+        tokio::select! {
+            ready = socket.ready(interest) => {
+                ready?;
+            }
+            read = local.read(&mut outbound), if start == end => {
+                match read? {
+                    // The stream was dropped.
+                    0 => return Ok(()),
+                    n => (start, end) = (0, n),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn ssh_destinations() {
+        let tunnel: RDPSshTunnel = "admin@jump:2222".parse().unwrap();
+        assert_eq!(tunnel.username.as_deref(), Some("admin"));
+        assert_eq!(tunnel.address.host.name(), "jump");
+        assert_eq!(tunnel.port(), 2222);
+        assert_eq!(tunnel.to_string(), "admin@jump:2222");
+
+        let tunnel: RDPSshTunnel = "[fe80::1]".parse().unwrap();
+        assert_eq!(tunnel.username, None);
+        assert_eq!(tunnel.address.host.name(), "fe80::1");
+        assert_eq!(tunnel.port(), SSH_PORT);
+
+        assert!("".parse::<RDPSshTunnel>().is_err());
+        assert!("@jump".parse::<RDPSshTunnel>().is_err());
+        assert!("jump host".parse::<RDPSshTunnel>().is_err());
+        assert!("jump:0".parse::<RDPSshTunnel>().is_err());
+    }
+
+    #[test]
+    fn host_keys_checked() {
+        use base64::Engine as _;
+
+        const JUMP_KEY: &str =
+            "AAAAC3NzaC1lZDI1NTE5AAAAIKBvx1qXy77hmsdvS6igOjD/V/HD/iliW0kfj/vcuPRe";
+        const OTHER_KEY: &str =
+            "AAAAC3NzaC1lZDI1NTE5AAAAIEt9ft+MpIxcvscFlOtnabkt5S2GdJNp4nJYYTH2Ez+A";
+        let key = |key| {
+            base64::engine::general_purpose::STANDARD
+                .decode(key)
+                .unwrap()
+        };
+        let known_hosts = format!(
+            "@cert-authority *.lab ssh-ed25519 {other}\n\
+             not a known_hosts line\n\
+             jump ssh-ed25519 {jump}\n\
+             [jump]:2222 ssh-ed25519 {other}\n",
+            jump = JUMP_KEY,
+            other = OTHER_KEY
+        );
+        let session = Session::new().unwrap();
+        let check = |host, port, host_key| {
+            check_known_host(&session, &known_hosts, host, port, &key(host_key))
+        };
+
+        assert!(check("jump", 22, JUMP_KEY).is_ok());
+        assert!(check("jump", 2222, OTHER_KEY).is_ok());
+        let mismatch = check("jump", 22, OTHER_KEY).unwrap_err();
+        assert!(mismatch.to_string().contains("doesn't match"));
+        let unknown = check("jump2", 22, JUMP_KEY).unwrap_err();
+        assert!(unknown.to_string().contains("isn't known"));
+    }
+
+    /// Something other than an SSH server is reported as such, not left hanging.
+    #[tokio::test]
+    async fn not_an_ssh_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tunnel: RDPSshTunnel = format!("tester@{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let stand_in = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                .await
+                .unwrap();
+        });
+        let error = tunnel.connect("vm1", 3389).await.unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("SSH jump host tester@127.0.0.1:"));
+        stand_in.await.unwrap();
+    }
+}
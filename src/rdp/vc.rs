@@ -39,10 +39,13 @@ impl DvcProcessor for GenericChannel {
     }
 }
 
+// Not yet wired up to anything that sends channel data.
+#[allow(dead_code)]
 pub struct GenericChannelMessage {
     payload: String,
 }
 
+#[allow(dead_code)]
 impl GenericChannelMessage {
     pub fn from_string(payload: String) -> Self {
         Self { payload }
@@ -63,7 +66,7 @@ impl Encode for GenericChannelMessage {
     }
 
     fn size(&self) -> usize {
-        self.payload.as_bytes().len()
+        self.payload.len()
     }
 }
 
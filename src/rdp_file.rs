@@ -0,0 +1,332 @@
+use std::path::Path;
+
+use crate::cli::Cli;
+use crate::rdp::{address::RDPServerAddress, RDPPerformanceFlag, RDPSecurity};
+
+/// Settings with an on/off value which correspond to our performance flags.
+static PERFORMANCE_SETTINGS: &[(&str, RDPPerformanceFlag)] = &[
+    ("disable wallpaper", RDPPerformanceFlag::DisableWallpaper),
+    (
+        "disable full window drag",
+        RDPPerformanceFlag::DisableFullWindowDrag,
+    ),
+    (
+        "disable menu anims",
+        RDPPerformanceFlag::DisableMenuAnimations,
+    ),
+    ("disable themes", RDPPerformanceFlag::DisableTheming),
+    (
+        "disable cursor setting",
+        RDPPerformanceFlag::DisableCursorSettings,
+    ),
+    (
+        "allow font smoothing",
+        RDPPerformanceFlag::EnableFontSmoothing,
+    ),
+    (
+        "allow desktop composition",
+        RDPPerformanceFlag::EnableDesktopComposition,
+    ),
+];
+
+/// Our own setting for the dynamic virtual channels to open, which mstsc has no
+/// equivalent for and ignores.
+const DYNAMIC_CHANNELS_SETTING: &str = "rdp-channel-client dynamic channels";
+
+/// Settings which we recognise but can't honour.
+static UNSUPPORTED_SETTINGS: &[&str] = &[
+    "audiomode",
+    "audiocapturemode",
+    "redirectclipboard",
+    "redirectprinters",
+    "redirectsmartcards",
+    "drivestoredirect",
+    "devicestoredirect",
+    "gatewaycredentialssource",
+    "password 51",
+];
+
+fn read_text(path: &Path) -> anyhow::Result<String> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+    // mstsc saves files as UTF-16 with a byte order mark.
+    match bytes.strip_prefix(&[0xff, 0xfe]) {
+        Some(utf16) => {
+            let units: Vec<u16> = utf16
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .collect();
+            Ok(String::from_utf16(&units)?)
+        }
+        None => Ok(String::from_utf8(bytes)?
+            .trim_start_matches('\u{feff}')
+            .to_string()),
+    }
+}
+
+/// Read a Microsoft `.rdp` file, as used by mstsc, as option settings in the same
+/// form as a profile.
+pub fn read_settings(path: &Path) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+    let text = read_text(path)?;
+    parse_settings(&text).map_err(|e| anyhow::anyhow!("{} ({})", e, path.display()))
+}
+
+/// Convert `.rdp` settings to option ids and values. Each line is a setting written
+/// as `name:type:value`, where the type is `s` (string), `i` (integer) or `b` (binary).
+fn parse_settings(text: &str) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+    let mut settings = Vec::new();
+    let mut width = None;
+    let mut height = None;
+    let mut performance_flags = None;
+    let mut gateway = None;
+    let mut gateway_usage = None;
+
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let mut parts = line.splitn(3, ':');
+        let (Some(name), Some(_kind), Some(value)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(anyhow::anyhow!("Malformed line '{}'", line));
+        };
+        let name = name.to_ascii_lowercase();
+        match name.as_str() {
+            "full address" => {
+                let address: RDPServerAddress = value.parse()?;
+                settings.push(setting("host", address.host.name()));
+                if let Some(port) = address.port {
+                    settings.push(setting("port", port.to_string()));
+                }
+            }
+            "server port" => settings.push(setting("port", value.to_string())),
+            "username" if !value.is_empty() => match value.split_once('\\') {
+                Some((domain, username)) => {
+                    settings.push(setting("domain", domain.to_string()));
+                    settings.push(setting("username", username.to_string()));
+                }
+                None => settings.push(setting("username", value.to_string())),
+            },
+            "domain" if !value.is_empty() => settings.push(setting("domain", value.to_string())),
+            "desktopwidth" => width = Some(value.to_string()),
+            "desktopheight" => height = Some(value.to_string()),
+            // Turning off CredSSP leaves TLS.
+            "enablecredsspsupport" if value == "0" => {
+                settings.push(setting("security", value_name(RDPSecurity::Tls)))
+            }
+            "gatewayhostname" if !value.is_empty() => gateway = Some(value.to_string()),
+            "gatewayusagemethod" => gateway_usage = Some(value.to_string()),
+            "keyboardhook" => settings.push(setting("grab_keyboard", (value == "1").to_string())),
+            DYNAMIC_CHANNELS_SETTING => {
+                let channels = value.split([';', ',']).filter(|c| !c.is_empty());
+                settings.push((
+                    "dynamic_channels".to_string(),
+                    channels.map(str::to_string).collect(),
+                ));
+            }
+            name if UNSUPPORTED_SETTINGS.contains(&name) => {
+                log::warn!("Ignoring unsupported .rdp setting '{}'", name);
+            }
+            name => match PERFORMANCE_SETTINGS.iter().find(|(n, _)| *n == name) {
+                Some((_, flag)) => {
+                    // Once any is given the file decides them all, even if none is on.
+                    let flags = performance_flags.get_or_insert_with(Vec::new);
+                    if value == "1" {
+                        flags.push(*flag);
+                    }
+                }
+                None => log::debug!("Ignoring .rdp setting '{}'", name),
+            },
+        }
+    }
+
+    if width.is_some() || height.is_some() {
+        let resolution = format!(
+            "{}x{}",
+            width.as_deref().unwrap_or("1024"),
+            height.as_deref().unwrap_or("768")
+        );
+        settings.push(("resolution".to_string(), vec![resolution]));
+    }
+    // Usage method 0 means not to use the gateway; the others are taken to mean
+    // always using it.
+    if let Some(gateway) = gateway.filter(|_| gateway_usage.as_deref() != Some("0")) {
+        settings.push(setting("gateway", gateway));
+    }
+    if let Some(mut performance_flags) = performance_flags {
+        if performance_flags.is_empty() {
+            performance_flags.push(RDPPerformanceFlag::None);
+        }
+        settings.push((
+            "performance_flags".to_string(),
+            performance_flags
+                .iter()
+                .map(|flag| value_name(*flag))
+                .collect(),
+        ));
+    }
+    // Where a setting is given twice (e.g. the port in both the address and "server
+    // port") the last one wins.
+    let mut deduplicated: Vec<(String, Vec<String>)> = Vec::new();
+    for (id, values) in settings.into_iter().rev() {
+        if !deduplicated.iter().any(|(seen, _)| *seen == id) {
+            deduplicated.insert(0, (id, values));
+        }
+    }
+    Ok(deduplicated)
+}
+
+fn setting(id: &str, value: String) -> (String, Vec<String>) {
+    (id.to_string(), vec![value])
+}
+
+fn value_name(value: impl clap::ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+/// Write the connection settings as an `.rdp` file, in the UTF-16 mstsc uses.
+pub fn export(cli: &Cli, path: &Path) -> anyhow::Result<()> {
+    let text = export_text(cli);
+    let bytes: Vec<u8> = [0xff, 0xfe]
+        .into_iter()
+        .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+        .collect();
+    std::fs::write(path, bytes)
+        .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))
+}
+
+fn export_text(cli: &Cli) -> String {
+    let mut lines = vec![
+        format!(
+            "full address:s:{}:{}",
+            cli.host.host,
+            cli.host.port.unwrap_or(cli.port)
+        ),
+        format!("username:s:{}", cli.username.as_deref().unwrap_or_default()),
+        format!("domain:s:{}", cli.domain.as_deref().unwrap_or_default()),
+        format!("desktopwidth:i:{}", cli.resolution.width),
+        format!("desktopheight:i:{}", cli.resolution.height),
+        "screen mode id:i:1".to_string(),
+        format!(
+            "enablecredsspsupport:i:{}",
+            if cli.security == RDPSecurity::Tls {
+                0
+            } else {
+                1
+            }
+        ),
+        format!("keyboardhook:i:{}", if cli.grab_keyboard { 1 } else { 0 }),
+    ];
+    if let Some(gateway) = &cli.gateway {
+        lines.push(format!("gatewayhostname:s:{}", gateway));
+        lines.push("gatewayusagemethod:i:1".to_string());
+    }
+    if let Some(channels) = &cli.dynamic_channels {
+        lines.push(format!(
+            "{}:s:{}",
+            DYNAMIC_CHANNELS_SETTING,
+            channels.join(";")
+        ));
+    }
+    let performance_flags: &[RDPPerformanceFlag] = if cli.performance_flags.is_empty() {
+        &[RDPPerformanceFlag::DisableFullWindowDrag]
+    } else {
+        &cli.performance_flags
+    };
+    for (name, flag) in PERFORMANCE_SETTINGS {
+        let on = performance_flags.contains(flag);
+        lines.push(format!("{}:i:{}", name, if on { 1 } else { 0 }));
+    }
+    lines.iter().map(|line| format!("{}\r\n", line)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn parse_mstsc_settings() {
+        let text = "screen mode id:i:2\r\n\
+                    server port:i:3391\r\n\
+                    full address:s:[fe80::1]:3390\r\n\
+                    username:s:LAB\\tester\r\n\
+                    desktopwidth:i:1280\r\n\
+                    desktopheight:i:800\r\n\
+                    redirectclipboard:i:1\r\n\
+                    disable wallpaper:i:1\r\n\
+                    disable themes:i:0\r\n\
+                    enablecredsspsupport:i:0\r\n\
+                    gatewayhostname:s:gw.lab.example\r\n\
+                    gatewayusagemethod:i:2\r\n\
+                    rdp-channel-client dynamic channels:s:echo;telemetry\r\n";
+        let settings = parse_settings(text).unwrap();
+        let get = |id: &str| {
+            settings
+                .iter()
+                .find(|(i, _)| i == id)
+                .map(|(_, values)| values.join(","))
+        };
+        assert_eq!(get("host").as_deref(), Some("fe80::1"));
+        assert_eq!(get("port").as_deref(), Some("3390"));
+        assert_eq!(get("domain").as_deref(), Some("LAB"));
+        assert_eq!(get("username").as_deref(), Some("tester"));
+        assert_eq!(get("resolution").as_deref(), Some("1280x800"));
+        assert_eq!(get("dynamic_channels").as_deref(), Some("echo,telemetry"));
+        assert_eq!(get("security").as_deref(), Some("tls"));
+        assert_eq!(
+            get("performance_flags").as_deref(),
+            Some("disable-wallpaper")
+        );
+        assert_eq!(get("gateway").as_deref(), Some("gw.lab.example"));
+        assert!(parse_settings("nonsense").is_err());
+
+        let unused = parse_settings("gatewayhostname:s:gw\r\ngatewayusagemethod:i:0").unwrap();
+        assert!(unused.is_empty());
+
+        // mstsc writes an empty username, and performance settings which are all off.
+        let settings = parse_settings("username:s:\r\ndisable wallpaper:i:0\r\n").unwrap();
+        assert_eq!(
+            settings,
+            [("performance_flags".to_string(), vec!["none".to_string()])]
+        );
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let cli = Cli::parse_from([
+            "rcc",
+            "-u",
+            "tester",
+            "-d",
+            "LAB",
+            "-D",
+            "echo",
+            "--resolution",
+            "1280x800",
+            "--gateway",
+            "gw:8443",
+            "vm1",
+        ]);
+        let settings = parse_settings(&export_text(&cli)).unwrap();
+        let expected = [
+            ("host", "vm1"),
+            ("port", "3389"),
+            ("username", "tester"),
+            ("domain", "LAB"),
+            ("grab_keyboard", "false"),
+            ("dynamic_channels", "echo"),
+            ("gateway", "gw:8443"),
+            ("resolution", "1280x800"),
+            ("performance_flags", "disable-full-window-drag"),
+        ];
+        for (id, value) in expected {
+            assert!(
+                settings.contains(&(id.to_string(), vec![value.to_string()])),
+                "{} {:?}",
+                id,
+                settings
+            );
+        }
+    }
+}